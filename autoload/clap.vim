@@ -36,7 +36,7 @@ let g:clap#provider_alias = s:provider_alias
 let g:clap_disable_run_rooter = get(g:, 'clap_disable_run_rooter', v:false)
 let g:clap_disable_bottom_top = get(g:, 'clap_disable_bottom_top', 0)
 let g:clap_enable_debug = get(g:, 'clap_enable_debug', v:false)
-let g:clap_forerunner_status_sign = get(g:, 'clap_forerunner_status_sign', {'done': '•', 'running': '!', 'using_cache': '*'})
+let g:clap_forerunner_status_sign = get(g:, 'clap_forerunner_status_sign', {'done': '•', 'running': '!', 'using_cache': '*', 'stale': '~'})
 
 " Backward compatible
 if exists('g:clap_forerunner_status_sign_done')
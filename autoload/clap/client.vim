@@ -48,7 +48,7 @@ function! clap#client#call_on_move(method, callback, ...) abort
   if empty(curline)
     return
   endif
-  let params = {'curline': curline}
+  let params = {'curline': curline, 'lnum': g:clap.display.getcurlnum()}
   if a:0 > 0
     call extend(params, a:1)
   endif
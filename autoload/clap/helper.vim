@@ -62,6 +62,15 @@ function! clap#helper#echo_error(msg) abort
   echohl NONE
 endfunction
 
+" The error of maple is either a string or a dict containing the message and the stderr
+" excerpt of the failed command.
+function! clap#helper#maple_error_lines(error) abort
+  if type(a:error) == v:t_dict
+    return [a:error.message] + split(a:error.stderr, "\n")
+  endif
+  return split(a:error, "\n")
+endfunction
+
 function! clap#helper#echo_warn(msg) abort
   echohl WarningMsg
   echom 'vim-clap: '.a:msg
@@ -6,6 +6,18 @@ set cpoptions&vim
 
 let s:default_priority = 10
 
+" The matched indices are char indices, returns the 0-based byte column and
+" the byte length of the char at a:char_idx, composing characters are
+" counted separately as the Rust backend does.
+function! s:byte_range_of(line, char_idx) abort
+  let col = byteidxcomp(a:line, a:char_idx)
+  if col == -1
+    return [-1, 0]
+  endif
+  let next = byteidxcomp(a:line, a:char_idx + 1)
+  return [col, next > col ? next - col : 1]
+endfunction
+
 if has('nvim')
   function! s:apply_add_highlight(hl_lines) abort
     " Currently neovim does not have win_execute()
@@ -23,13 +35,18 @@ if has('nvim')
 
     let lnum = 0
     for indices in a:hl_lines
+      let line = get(getbufline(g:clap.display.bufnr, lnum+1), 0, '')
       let group_idx = 1
       for idx in indices
+        let [col, length] = s:byte_range_of(line, idx)
+        if col == -1
+          continue
+        endif
         if group_idx < g:__clap_fuzzy_matches_hl_group_cnt + 1
-          call add(w:clap_match_ids, clap#highlight#matchadd_char_at(lnum, idx, 'ClapFuzzyMatches'.group_idx))
+          call add(w:clap_match_ids, clap#highlight#matchadd_char_at(lnum, col, 'ClapFuzzyMatches'.group_idx, length))
           let group_idx += 1
         else
-          call add(w:clap_match_ids, clap#highlight#matchadd_char_at(lnum, idx, g:__clap_fuzzy_last_hl_group))
+          call add(w:clap_match_ids, clap#highlight#matchadd_char_at(lnum, col, g:__clap_fuzzy_last_hl_group, length))
         endif
       endfor
       let lnum += 1
@@ -45,9 +62,10 @@ if has('nvim')
     call g:clap.input.goto_win()
   endfunction
 
-  function! clap#highlight#add_highlight_at(lnum, col, hl_group) abort
+  " Optional argument: byte length of the highlight, 1 by default.
+  function! clap#highlight#add_highlight_at(lnum, col, hl_group, ...) abort
     " 0-based
-    call nvim_buf_add_highlight(g:clap.display.bufnr, -1, a:hl_group, a:lnum, a:col, a:col+1)
+    call nvim_buf_add_highlight(g:clap.display.bufnr, -1, a:hl_group, a:lnum, a:col, a:col+get(a:000, 0, 1))
   endfunction
 
 else
@@ -56,13 +74,18 @@ else
     " as the previous lines have been deleted, and the associated text_props have also been removed.
     let lnum = 0
     for indices in a:hl_lines
+      let line = get(getbufline(g:clap.display.bufnr, lnum+1), 0, '')
       let group_idx = 1
       for idx in indices
+        let [col, length] = s:byte_range_of(line, idx)
+        if col == -1
+          continue
+        endif
         if group_idx < g:__clap_fuzzy_matches_hl_group_cnt + 1
-          call clap#highlight#add_highlight_at(lnum, idx, 'ClapFuzzyMatches'.group_idx)
+          call clap#highlight#add_highlight_at(lnum, col, 'ClapFuzzyMatches'.group_idx, length)
           let group_idx += 1
         else
-          call clap#highlight#add_highlight_at(lnum, idx, g:__clap_fuzzy_last_hl_group)
+          call clap#highlight#add_highlight_at(lnum, col, g:__clap_fuzzy_last_hl_group, length)
         endif
       endfor
       let lnum += 1
@@ -72,9 +95,10 @@ else
   function! clap#highlight#clear() abort
   endfunction
 
-  function! clap#highlight#add_highlight_at(lnum, col, hl_group) abort
+  " Optional argument: byte length of the highlight, 1 by default.
+  function! clap#highlight#add_highlight_at(lnum, col, hl_group, ...) abort
     " 1-based
-    call prop_add(a:lnum+1, a:col+1, {'length': 1, 'type': a:hl_group, 'bufnr': g:clap.display.bufnr})
+    call prop_add(a:lnum+1, a:col+1, {'length': get(a:000, 0, 1), 'type': a:hl_group, 'bufnr': g:clap.display.bufnr})
   endfunction
 
 endif
@@ -117,8 +141,9 @@ function! clap#highlight#add_fuzzy_async_with_delay(hl_lines) abort
 endfunction
 
 " lnum and col are 0-based.
-function! clap#highlight#matchadd_char_at(lnum, col, hl_group) abort
-  return matchaddpos(a:hl_group, [[a:lnum+1, a:col+1, 1]])
+" Optional argument: byte length of the highlight, 1 by default.
+function! clap#highlight#matchadd_char_at(lnum, col, hl_group, ...) abort
+  return matchaddpos(a:hl_group, [[a:lnum+1, a:col+1, get(a:000, 0, 1)]])
 endfunction
 
 " Add highlight for the substring matches.
@@ -47,18 +47,27 @@ function! s:on_complete_maple() abort
 
     if has_key(decoded, 'error')
       if g:clap_enable_debug
-        call clap#helper#echo_error('on_forerunner_error:', decoded.error)
+        call clap#helper#echo_error('on_forerunner_error: '.join(clap#helper#maple_error_lines(decoded.error), ' '))
       endif
       return
     endif
 
+    " The command failed by itself, but its output is still used.
+    if get(decoded, 'warning', v:null) isnot v:null && g:clap_enable_debug
+      call clap#helper#echo_warn('on_forerunner_warning: '.join(clap#helper#maple_error_lines(decoded.warning), ' '))
+    endif
+
     call clap#sign#ensure_exists()
 
     " Using the cached file
     if has_key(decoded, 'using_cache')
       let g:__clap_forerunner_tempfile = decoded.tempfile
       let g:clap.display.initial_size = decoded.total
-      let g:__clap_current_forerunner_status = g:clap_forerunner_status_sign.using_cache
+      if get(decoded, 'stale', v:false)
+        let g:__clap_current_forerunner_status = get(g:clap_forerunner_status_sign, 'stale', '~')
+      else
+        let g:__clap_current_forerunner_status = g:clap_forerunner_status_sign.using_cache
+      endif
       call clap#spinner#refresh()
       if has_key(decoded, 'lines')
         let cur_lines = g:clap.display.get_lines()
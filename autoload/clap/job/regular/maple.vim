@@ -42,12 +42,17 @@ function! s:on_complete() abort
           \ 'executable: '.s:cmd[0],
           \ 'args: '.join(s:cmd[1:], ' '),
           \ 'error:',
-          \ ] + split(decoded.error, "\n"))
+          \ ] + clap#helper#maple_error_lines(decoded.error))
     call clap#indicator#set_matches_number(0)
     call clap#sign#disable_cursorline()
     return
   endif
 
+  " The command failed by itself, but its output is still used.
+  if get(decoded, 'warning', v:null) isnot v:null && g:clap_enable_debug
+    call clap#helper#echo_warn('maple warning: '.join(clap#helper#maple_error_lines(decoded.warning), ' '))
+  endif
+
   if decoded.total == 0
     call g:clap.display.set_lines([g:clap_no_matches_msg])
     call clap#indicator#set_matches_number(0)
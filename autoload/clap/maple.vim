@@ -63,15 +63,16 @@ endfunction
 " Returns the filtered results after the input stream is complete.
 function! clap#maple#sync_filter_command(query) abort
   let global_opt = ['--number', g:clap.display.preload_capacity, '--winwidth', winwidth(g:clap.display.winid)]
+  let filter_opt = ['--sync']
 
   if g:clap.provider.id ==# 'files'
-    call add(global_opt, printf('--bonus=%s', clap#filter#get_bonus_type()))
+    call add(filter_opt, printf('--bonus=%s', clap#filter#get_bonus_type()))
     if g:clap_enable_icon
       call add(global_opt, '--icon-painter=File')
     endif
   endif
 
-  return [s:maple_bin] + global_opt + ['filter', a:query, '--sync']
+  return [s:maple_bin] + global_opt + ['filter', a:query] + filter_opt
 endfunction
 
 function! clap#maple#tags_forerunner_command() abort
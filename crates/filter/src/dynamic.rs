@@ -1,13 +1,17 @@
+use std::cmp::Ordering;
 use std::io::{self, BufRead};
+use std::sync::mpsc::sync_channel;
 use std::time::{Duration, Instant};
 
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rayon::slice::ParallelSliceMut;
 
-use icon::{IconPainter, ICON_LEN};
-use matcher::{Bonus, MatchType};
+use icon::{IconPainter, ICON_CHAR_LEN};
+use matcher::{Algo, MatchResult, Matcher};
 use utility::{println_json, println_json_with_length};
 
 use super::*;
+use crate::source::{filter_bytes, line_chunks, mmap_file, read_byte_lines, Separator, CHUNK_SIZE};
 use crate::FilterResult;
 
 /// The constant to define the length of `top_` queues.
@@ -18,6 +22,90 @@ const MAX_IDX: usize = ITEMS_TO_SHOW - 1;
 /// Refresh the top filtered results per 300 ms.
 const UPDATE_INTERVAL: Duration = Duration::from_millis(300);
 
+/// Size of the first batch of lines to score in parallel, which is small to show the first
+/// results quickly, the later batches are doubled until [`MAX_BATCH_SIZE`].
+const MIN_BATCH_SIZE: usize = 64;
+
+const MAX_BATCH_SIZE: usize = 4096;
+
+/// Returns the iterator of filtered results of the lines in `bytes`, usually a memory-mapped
+/// file.
+///
+/// The chunks of lines are scored in batches on the rayon pool, the filtered results are still
+/// in the original order.
+fn par_filter_bytes<'a, F>(
+    bytes: &'a [u8],
+    separator: Separator,
+    scorer: &'a F,
+) -> impl Iterator<Item = FilterResult> + 'a
+where
+    F: Fn(&str) -> MatchResult + Sync,
+{
+    let batch_size = rayon::current_num_threads();
+    let mut chunks = line_chunks(bytes, CHUNK_SIZE, separator).into_iter();
+    std::iter::from_fn(move || {
+        let batch = chunks.by_ref().take(batch_size).collect::<Vec<_>>();
+        if batch.is_empty() {
+            None
+        } else {
+            Some(batch)
+        }
+    })
+    .flat_map(move |batch| {
+        batch
+            .into_par_iter()
+            .flat_map(|chunk| filter_bytes(chunk, separator, scorer))
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Returns the iterator of filtered results of the lines in `reader`.
+///
+/// The lines are read on a dedicated thread and scored in batches on the rayon pool, the
+/// filtered results are still in the original order.
+fn par_filter_lines<'a, R, F>(
+    reader: R,
+    separator: Separator,
+    scorer: &'a F,
+) -> impl Iterator<Item = FilterResult> + 'a
+where
+    R: BufRead + Send + 'static,
+    F: Fn(&SourceItem) -> MatchResult + Sync,
+{
+    // Limit the number of pending batches to not read the whole stream into memory.
+    let (sender, receiver) = sync_channel::<Vec<Vec<u8>>>(4);
+
+    std::thread::spawn(move || {
+        let mut batch_size = MIN_BATCH_SIZE;
+        let mut batch = Vec::with_capacity(batch_size);
+        // The line stream can contain invalid UTF-8 data, which is kept and decoded lossily.
+        for line in read_byte_lines(reader, separator) {
+            batch.push(line);
+            if batch.len() == batch_size {
+                batch_size = (batch_size * 2).min(MAX_BATCH_SIZE);
+                let full_batch = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+                // The receiver is dropped, no need to read more.
+                if sender.send(full_batch).is_err() {
+                    return;
+                }
+            }
+        }
+        if !batch.is_empty() {
+            let _ = sender.send(batch);
+        }
+    });
+
+    receiver.into_iter().flat_map(move |batch| {
+        batch
+            .into_par_iter()
+            .filter_map(|line| {
+                let item: SourceItem = line.into();
+                scorer(&item).map(|(score, indices)| (item, score, indices))
+            })
+            .collect::<Vec<_>>()
+    })
+}
+
 trait Insert<T> {
     fn pop_and_insert(&mut self, idx: usize, value: T);
 }
@@ -35,34 +123,32 @@ impl<T: Copy> Insert<T> for [T; ITEMS_TO_SHOW] {
 
 /// This macro is a special thing for [`dyn_collect_all`] and [`dyn_collect_number`].
 macro_rules! insert_both {
-    // This macro pushes all things into buffer, pops one worst item from each top queue
-    // and then inserts all things into `top_` queues.
-    (pop; $index:expr, $score:expr, $text:expr, $indices:expr => $buffer:expr, $top_results:expr, $top_scores:expr) => {{
+    // This macro pushes the result into buffer, pops the worst item from the top queue
+    // and then inserts the result into the top queue.
+    (pop; $index:expr, $result:expr => $buffer:expr, $top_results:expr) => {{
         match $index {
             // If index is last possible, then the worst item is better than this we want to push in,
             // and we do nothing.
-            Some(MAX_IDX) => $buffer.push(($text, $score, $indices)),
+            Some(MAX_IDX) => $buffer.push($result),
             // Else, one item gets popped from the queue
             // and other is inserted.
             Some(idx) => {
-                insert_both!(idx + 1, $score, $text, $indices => $buffer, $top_results, $top_scores);
+                insert_both!(idx + 1, $result => $buffer, $top_results);
             }
             None => {
-                insert_both!(0, $score, $text, $indices => $buffer, $top_results, $top_scores);
+                insert_both!(0, $result => $buffer, $top_results);
             }
         }
     }};
 
-    // This macro pushes all things into buffer and inserts all things into
-    // `top_` queues.
-    ($index:expr, $score:expr, $text:expr, $indices:expr => $buffer:expr, $top_results:expr, $top_scores:expr) => {{
-        $buffer.push(($text, $score, $indices));
+    // This macro pushes the result into buffer and inserts it into the top queue.
+    ($index:expr, $result:expr => $buffer:expr, $top_results:expr) => {{
+        $buffer.push($result);
         $top_results.pop_and_insert($index, $buffer.len() - 1);
-        $top_scores.pop_and_insert($index, $score);
     }};
 }
 
-type SelectedTopItemsInfo = (usize, [i64; ITEMS_TO_SHOW], [usize; ITEMS_TO_SHOW]);
+type SelectedTopItemsInfo = (usize, [usize; ITEMS_TO_SHOW]);
 
 /// Returns Ok if all items in the iterator has been processed.
 ///
@@ -70,18 +156,18 @@ type SelectedTopItemsInfo = (usize, [i64; ITEMS_TO_SHOW], [usize; ITEMS_TO_SHOW]
 fn select_top_items_to_show(
     buffer: &mut Vec<FilterResult>,
     iter: &mut impl Iterator<Item = FilterResult>,
+    matcher: &Matcher,
 ) -> std::result::Result<usize, SelectedTopItemsInfo> {
-    let mut top_scores: [i64; ITEMS_TO_SHOW] = [i64::min_value(); ITEMS_TO_SHOW];
     let mut top_results: [usize; ITEMS_TO_SHOW] = [usize::min_value(); ITEMS_TO_SHOW];
 
     let mut total = 0;
-    let res = iter.try_for_each(|(text, score, indices)| {
-        let idx = match find_best_score_idx(&top_scores, score) {
+    let res = iter.try_for_each(|result| {
+        let idx = match find_best_score_idx(&top_results[..total], buffer, &result, matcher) {
             Some(idx) => idx + 1,
             None => 0,
         };
 
-        insert_both!(idx, score, text, indices => buffer, top_results, top_scores);
+        insert_both!(idx, result => buffer, top_results);
 
         // Stop iterating after `ITEMS_TO_SHOW` iterations.
         total += 1;
@@ -95,20 +181,27 @@ fn select_top_items_to_show(
     if res.is_ok() {
         Ok(total)
     } else {
-        Err((total, top_scores, top_results))
+        Err((total, top_results))
     }
 }
 
-/// Returns the index of best score in `top_scores`.
+/// Returns the index of the last item in `top_results` ranking no lower than `result`.
 ///
-/// Best results are stored in front, the bigger the better.
+/// Best results are stored in front, the items are ranked by [`compare_results`] so that the
+/// tiebreakers apply to the intermediate top results as well as the final ones.
 #[inline]
-fn find_best_score_idx(top_scores: &[i64; ITEMS_TO_SHOW], score: i64) -> Option<usize> {
-    top_scores
+fn find_best_score_idx(
+    top_results: &[usize],
+    buffer: &[FilterResult],
+    result: &FilterResult,
+    matcher: &Matcher,
+) -> Option<usize> {
+    top_results
         .iter()
         .enumerate()
         .rev() // .rev(), because worse items are at the end.
-        .find(|&(_, &other_score)| other_score > score)
+        // The equal item comes later, keep the original order.
+        .find(|&(_, &idx)| compare_results(matcher, &buffer[idx], result) != Ordering::Greater)
         .map(|(idx, _)| idx)
 }
 
@@ -132,7 +225,7 @@ fn try_notify_top_results(
             for &idx in top_results.iter() {
                 let (item, _, idxs) = std::ops::Index::index(buffer, idx);
                 let text = if let Some(painter) = icon_painter {
-                    indices.push(idxs.iter().map(|x| x + ICON_LEN).collect::<Vec<_>>());
+                    indices.push(idxs.iter().map(|x| x + ICON_CHAR_LEN).collect::<Vec<_>>());
                     painter.paint(item.display_text())
                 } else {
                     indices.push(idxs.clone());
@@ -169,21 +262,23 @@ fn try_notify_top_results(
 /// But there's a problem with `par_iter` again, as there should be mutexed access to the
 /// VecDeque for this iterator.
 ///
-/// So, this particular function won't work in parallel context at all.
+/// So, this particular function won't work in parallel context at all, the items are scored
+/// in parallel beforehand instead, see [`par_filter_lines`].
 fn dyn_collect_all(
     mut iter: impl Iterator<Item = FilterResult>,
     icon_painter: &Option<IconPainter>,
+    matcher: &Matcher,
 ) -> Vec<FilterResult> {
     let mut buffer = Vec::with_capacity({
         let (low, high) = iter.size_hint();
         high.unwrap_or(low)
     });
 
-    let should_return = select_top_items_to_show(&mut buffer, &mut iter);
+    let should_return = select_top_items_to_show(&mut buffer, &mut iter, matcher);
 
-    let (mut total, mut top_scores, mut top_results) = match should_return {
+    let (mut total, mut top_results) = match should_return {
         Ok(_) => return buffer,
-        Err((t, top_scores, top_results)) => (t, top_scores, top_results),
+        Err((t, top_results)) => (t, top_results),
     };
 
     let mut last_lines = Vec::with_capacity(top_results.len());
@@ -191,10 +286,10 @@ fn dyn_collect_all(
     // Now we have the full queue and can just pair `.pop_back()` with `.insert()` to keep
     // the queue with best results the same size.
     let mut past = std::time::Instant::now();
-    iter.for_each(|(text, score, indices)| {
-        let idx = find_best_score_idx(&top_scores, score);
+    iter.for_each(|result| {
+        let idx = find_best_score_idx(&top_results, &buffer, &result, matcher);
 
-        insert_both!(pop; idx, score, text, indices => buffer, top_results, top_scores);
+        insert_both!(pop; idx, result => buffer, top_results);
 
         total = total.wrapping_add(1);
 
@@ -232,16 +327,17 @@ fn dyn_collect_number(
     mut iter: impl Iterator<Item = FilterResult>,
     number: usize,
     icon_painter: &Option<IconPainter>,
+    matcher: &Matcher,
 ) -> (usize, Vec<(SourceItem, i64, Vec<usize>)>) {
     // To not have problems with queues after sorting and truncating the buffer,
     // buffer has the lowest bound of `ITEMS_TO_SHOW * 2`, not `number * 2`.
     let mut buffer = Vec::with_capacity(2 * std::cmp::max(ITEMS_TO_SHOW, number));
 
-    let should_return = select_top_items_to_show(&mut buffer, &mut iter);
+    let should_return = select_top_items_to_show(&mut buffer, &mut iter, matcher);
 
-    let (mut total, mut top_scores, mut top_results) = match should_return {
+    let (mut total, mut top_results) = match should_return {
         Ok(t) => return (t, buffer),
-        Err((t, top_scores, top_results)) => (t, top_scores, top_results),
+        Err((t, top_results)) => (t, top_results),
     };
 
     let mut last_lines = Vec::with_capacity(top_results.len());
@@ -249,10 +345,10 @@ fn dyn_collect_number(
     // Now we have the full queue and can just pair `.pop_back()` with `.insert()` to keep
     // the queue with best results the same size.
     let mut past = std::time::Instant::now();
-    iter.for_each(|(text, score, indices)| {
-        let idx = find_best_score_idx(&top_scores, score);
+    iter.for_each(|result| {
+        let idx = find_best_score_idx(&top_results, &buffer, &result, matcher);
 
-        insert_both!(pop; idx, score, text, indices => buffer, top_results, top_scores);
+        insert_both!(pop; idx, result => buffer, top_results);
 
         total += 1;
 
@@ -272,11 +368,10 @@ fn dyn_collect_number(
         }
 
         if buffer.len() == buffer.capacity() {
-            buffer.par_sort_unstable_by(|(_, v1, _), (_, v2, _)| v2.partial_cmp(&v1).unwrap());
+            buffer.par_sort_by(|a, b| compare_results(matcher, a, b));
 
-            for (idx, (_, score, _)) in buffer[..ITEMS_TO_SHOW].iter().enumerate() {
-                top_scores[idx] = *score;
-                top_results[idx] = idx;
+            for (idx, top_result) in top_results.iter_mut().enumerate() {
+                *top_result = idx;
             }
 
             let half = buffer.len() / 2;
@@ -289,45 +384,43 @@ fn dyn_collect_number(
 
 // macros for `dyn_collect_number` and `dyn_collect_number`
 //
-// Generate an filtered iterator from Source::Stdin.
+// Generate an filtered iterator from Source::Stdin(separator), scored in parallel.
 macro_rules! source_iter_stdin {
-    ( $scorer:ident ) => {
-        io::stdin().lock().lines().filter_map(|lines_iter| {
-            lines_iter
-                .ok()
-                .map(Into::<SourceItem>::into)
-                .and_then(|item| $scorer(&item).map(|(score, indices)| (item, score, indices)))
-        })
+    ( $scorer:ident, $separator:ident ) => {
+        par_filter_lines(io::BufReader::new(io::stdin()), $separator, &$scorer)
     };
 }
 
-// Generate an filtered iterator from Source::Exec(exec).
+// Generate an filtered iterator from Source::Exec(exec, separator), scored in parallel.
 #[cfg(feature = "enable_dyn")]
 macro_rules! source_iter_exec {
-    ( $scorer:ident, $exec:ident ) => {
-        std::io::BufReader::new($exec.stream_stdout()?)
-            .lines()
-            .filter_map(|lines_iter| {
-                lines_iter
-                    .ok()
-                    .map(Into::<SourceItem>::into)
-                    .and_then(|item| $scorer(&item).map(|(score, indices)| (item, score, indices)))
-            })
+    ( $scorer:ident, $exec:ident, $separator:ident ) => {
+        par_filter_lines(
+            std::io::BufReader::new($exec.stream_stdout()?),
+            $separator,
+            &$scorer,
+        )
     };
 }
 
-// Generate an filtered iterator from Source::File(fpath).
+// Generate an filtered iterator from the memory-mapped Source::File(fpath, separator).
+//
+// The line stream can contain invalid UTF-8 data, which is decoded lossily.
 macro_rules! source_iter_file {
-    ( $scorer:ident, $fpath:ident ) => {
-        // To avoid Err(Custom { kind: InvalidData, error: "stream did not contain valid UTF-8" })
-        // The line stream can contain invalid UTF-8 data.
-        std::io::BufReader::new(std::fs::File::open($fpath)?)
-            .lines()
-            .filter_map(|x| {
-                x.ok()
-                    .map(Into::<SourceItem>::into)
-                    .and_then(|item| $scorer(&item).map(|(score, indices)| (item, score, indices)))
-            })
+    ( $line_scorer:ident, $mmap:ident, $separator:ident ) => {
+        par_filter_bytes(
+            $mmap.as_deref().unwrap_or_default(),
+            $separator,
+            &$line_scorer,
+        )
+    };
+}
+
+// Generate an filtered iterator from the compressed Source::File(fpath, separator), which is
+// decompressed by streaming and scored in parallel.
+macro_rules! source_iter_decompressed {
+    ( $scorer:ident, $reader:ident, $separator:ident ) => {
+        par_filter_lines($reader, $separator, &$scorer)
     };
 }
 
@@ -342,48 +435,98 @@ macro_rules! source_iter_list {
 pub fn dyn_run<I: Iterator<Item = SourceItem>>(
     query: &str,
     source: Source<I>,
-    algo: Option<Algo>,
+    matcher: Matcher,
     number: Option<usize>,
     winwidth: Option<usize>,
     icon_painter: Option<IconPainter>,
-    match_type: MatchType,
-    bonus: Bonus,
 ) -> Result<()> {
-    let algo = if query.contains(' ') {
-        Algo::SubString
+    let scoring_matcher = if query.contains(' ') {
+        matcher.with_algo(Algo::SubString)
     } else {
-        algo.unwrap_or(Algo::Fzy)
+        matcher
     };
-    let scoring_matcher = matcher::Matcher::new(algo, match_type, bonus);
     let scorer = |item: &SourceItem| scoring_matcher.do_match(item, query);
+    let line_scorer = |line: &str| scoring_matcher.do_match_line(line, query);
+    let (mmap, decompressed) = match source {
+        Source::File(ref fpath, _) => match mmap_file(fpath)? {
+            Some(mmap) => (Some(mmap), None),
+            None => (None, Some(utility::open_decompressed(fpath)?)),
+        },
+        _ => (None, None),
+    };
     if let Some(number) = number {
         let (total, mut filtered) = match source {
-            Source::Stdin => dyn_collect_number(source_iter_stdin!(scorer), number, &icon_painter),
+            Source::Stdin(separator) => dyn_collect_number(
+                source_iter_stdin!(scorer, separator),
+                number,
+                &icon_painter,
+                &scoring_matcher,
+            ),
             #[cfg(feature = "enable_dyn")]
-            Source::Exec(exec) => {
-                dyn_collect_number(source_iter_exec!(scorer, exec), number, &icon_painter)
-            }
-            Source::File(fpath) => {
-                dyn_collect_number(source_iter_file!(scorer, fpath), number, &icon_painter)
-            }
-            Source::List(list) => {
-                dyn_collect_number(source_iter_list!(scorer, list), number, &icon_painter)
-            }
+            Source::Exec(exec, separator) => dyn_collect_number(
+                source_iter_exec!(scorer, exec, separator),
+                number,
+                &icon_painter,
+                &scoring_matcher,
+            ),
+            Source::File(_, separator) => match decompressed {
+                Some(reader) => dyn_collect_number(
+                    source_iter_decompressed!(scorer, reader, separator),
+                    number,
+                    &icon_painter,
+                    &scoring_matcher,
+                ),
+                None => dyn_collect_number(
+                    source_iter_file!(line_scorer, mmap, separator),
+                    number,
+                    &icon_painter,
+                    &scoring_matcher,
+                ),
+            },
+            Source::List(list) => dyn_collect_number(
+                source_iter_list!(scorer, list),
+                number,
+                &icon_painter,
+                &scoring_matcher,
+            ),
         };
 
-        filtered.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        filtered.par_sort_by(|a, b| compare_results(&scoring_matcher, a, b));
 
         printer::print_dyn_filter_results(filtered, total, number, winwidth, icon_painter);
     } else {
         let mut filtered = match source {
-            Source::Stdin => dyn_collect_all(source_iter_stdin!(scorer), &icon_painter),
+            Source::Stdin(separator) => dyn_collect_all(
+                source_iter_stdin!(scorer, separator),
+                &icon_painter,
+                &scoring_matcher,
+            ),
             #[cfg(feature = "enable_dyn")]
-            Source::Exec(exec) => dyn_collect_all(source_iter_exec!(scorer, exec), &icon_painter),
-            Source::File(fpath) => dyn_collect_all(source_iter_file!(scorer, fpath), &icon_painter),
-            Source::List(list) => dyn_collect_all(source_iter_list!(scorer, list), &icon_painter),
+            Source::Exec(exec, separator) => dyn_collect_all(
+                source_iter_exec!(scorer, exec, separator),
+                &icon_painter,
+                &scoring_matcher,
+            ),
+            Source::File(_, separator) => match decompressed {
+                Some(reader) => dyn_collect_all(
+                    source_iter_decompressed!(scorer, reader, separator),
+                    &icon_painter,
+                    &scoring_matcher,
+                ),
+                None => dyn_collect_all(
+                    source_iter_file!(line_scorer, mmap, separator),
+                    &icon_painter,
+                    &scoring_matcher,
+                ),
+            },
+            Source::List(list) => dyn_collect_all(
+                source_iter_list!(scorer, list),
+                &icon_painter,
+                &scoring_matcher,
+            ),
         };
 
-        filtered.par_sort_unstable_by(|(_, v1, _), (_, v2, _)| v2.partial_cmp(&v1).unwrap());
+        filtered.par_sort_by(|a, b| compare_results(&scoring_matcher, a, b));
 
         let ranked = filtered;
 
@@ -399,6 +542,66 @@ pub fn dyn_run<I: Iterator<Item = SourceItem>>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use matcher::{Bonus, CaseMatching, MatchType, Tiebreak};
+
+    #[test]
+    fn test_par_filter_lines() {
+        let total = MIN_BATCH_SIZE * 10 + 1;
+        let lines = (0..total).map(|i| format!("{}", i)).collect::<Vec<_>>();
+        let reader = std::io::Cursor::new(lines.join("\n").into_bytes());
+
+        let matcher = Matcher::new(
+            Algo::SubString,
+            MatchType::Full,
+            Bonus::None,
+            CaseMatching::Smart,
+        );
+        let scorer = |item: &SourceItem| matcher.do_match(item, "1");
+
+        let filtered = par_filter_lines(reader, Separator::Newline, &scorer)
+            .map(|(item, _, _)| item.raw)
+            .collect::<Vec<_>>();
+        let expected = lines
+            .into_iter()
+            .filter(|line| line.contains('1'))
+            .collect::<Vec<_>>();
+        assert_eq!(filtered, expected);
+    }
+
+    #[test]
+    fn test_top_results_tiebreaks() {
+        let matcher = Matcher::new(Algo::Fzy, MatchType::Full, Bonus::None, CaseMatching::Smart)
+            .with_scorer(|_: &str, _: &str, _: &CaseMatching| Some((0, Vec::new())))
+            .with_tiebreaks(vec![Tiebreak::Length]);
+
+        // All the lines have the same score, the shorter ones come later.
+        let mut results = (0..ITEMS_TO_SHOW)
+            .rev()
+            .map(|i| ("x".repeat(i + 2).into(), 0, Vec::new()))
+            .collect::<Vec<_>>();
+        results.push(("yy".into(), 0, Vec::new()));
+
+        let mut buffer = Vec::new();
+        let mut iter = results.into_iter();
+        let (_, mut top_results) =
+            select_top_items_to_show(&mut buffer, &mut iter, &matcher).unwrap_err();
+        let top_lines = |buffer: &[FilterResult], top_results: &[usize]| {
+            top_results
+                .iter()
+                .map(|&idx| buffer[idx].0.raw.clone())
+                .collect::<Vec<_>>()
+        };
+        let expected = (0..ITEMS_TO_SHOW)
+            .map(|i| "x".repeat(i + 2))
+            .collect::<Vec<_>>();
+        assert_eq!(top_lines(&buffer, &top_results), expected);
+
+        // The later one ranks higher than the worst top result by the tiebreaker.
+        let result = iter.next().unwrap();
+        let idx = find_best_score_idx(&top_results, &buffer, &result, &matcher);
+        insert_both!(pop; idx, result => buffer, top_results);
+        assert_eq!(top_lines(&buffer, &top_results)[..2], ["xx", "yy"]);
+    }
 
     #[test]
     // This is a very time-consuming test,
@@ -457,12 +660,10 @@ mod tests {
                 })
                 .take(usize::max_value() >> 8),
             ),
-            Some(Algo::Fzy),
+            Matcher::new(Algo::Fzy, MatchType::Full, Bonus::None, CaseMatching::Smart),
             Some(100),
             None,
             None,
-            MatchType::Full,
-            Bonus::None,
         )
         .unwrap()
     }
@@ -10,32 +10,83 @@ mod dynamic;
 mod source;
 
 use anyhow::Result;
-use matcher::{Algo, Bonus, MatchType, Matcher};
+use matcher::Matcher;
 use rayon::prelude::*;
 use source_item::SourceItem;
 
 pub use dynamic::dyn_run;
 pub use matcher;
-pub use source::Source;
+pub use source::{read_byte_lines, Separator, Source};
 #[cfg(feature = "enable_dyn")]
 pub use subprocess;
 
 /// Tuple of (matched line text, filtering score, indices of matched elements)
 pub type FilterResult = (SourceItem, i64, Vec<usize>);
 
-/// Returns the ranked results after applying the matcher algo
+/// Compares two filter results with the score and the tiebreakers of `matcher`.
+#[inline]
+fn compare_results(matcher: &Matcher, a: &FilterResult, b: &FilterResult) -> std::cmp::Ordering {
+    matcher.compare_results((&a.0, a.1, &a.2), (&b.0, b.1, &b.2))
+}
+
+/// Returns the ranked results after applying the matcher
 /// given the query String and filtering source.
 pub fn sync_run<I: Iterator<Item = SourceItem>>(
     query: &str,
     source: Source<I>,
-    algo: Algo,
-    match_type: MatchType,
-    bonus: Bonus,
+    matcher: Matcher,
 ) -> Result<Vec<FilterResult>> {
-    let matcher = Matcher::new(algo, match_type, bonus);
-    let mut ranked = source.filter(matcher, query)?;
+    let mut ranked = source.filter(&matcher, query)?;
 
-    ranked.par_sort_unstable_by(|(_, v1, _), (_, v2, _)| v2.partial_cmp(&v1).unwrap());
+    ranked.par_sort_by(|a, b| compare_results(&matcher, a, b));
 
     Ok(ranked)
 }
+
+/// Returns the ranked results of the items scored by reference in parallel, each result is a
+/// tuple of `(index_of_item, score, indices)`.
+///
+/// No item is cloned, the caller can clone the top ones only for the further processing.
+pub fn rank_items(
+    query: &str,
+    items: &[SourceItem],
+    matcher: &Matcher,
+) -> Vec<(usize, i64, Vec<usize>)> {
+    let mut ranked = items
+        .par_iter()
+        .enumerate()
+        .filter_map(|(idx, item)| {
+            matcher
+                .do_match(item, query)
+                .map(|(score, indices)| (idx, score, indices))
+        })
+        .collect::<Vec<_>>();
+
+    ranked.par_sort_by(|a, b| {
+        matcher.compare_results((&items[a.0], a.1, &a.2), (&items[b.0], b.1, &b.2))
+    });
+
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matcher::{Algo, Bonus, CaseMatching, MatchType};
+
+    #[test]
+    fn test_rank_items() {
+        let items: std::sync::Arc<[SourceItem]> = vec!["src/lib.rs", "lib.rs", "README.md"]
+            .into_iter()
+            .map(Into::into)
+            .collect::<Vec<_>>()
+            .into();
+        let matcher = Matcher::new(Algo::Fzy, MatchType::Full, Bonus::None, CaseMatching::Smart);
+
+        let ranked = rank_items("lib", &items, &matcher);
+        assert_eq!(
+            ranked.iter().map(|(idx, ..)| *idx).collect::<Vec<_>>(),
+            vec![1, 0]
+        );
+    }
+}
@@ -1,29 +1,194 @@
 use super::*;
+use matcher::MatchResult;
+use memmap2::Mmap;
+use std::borrow::Cow;
 use std::io::BufRead;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 #[cfg(feature = "enable_dyn")]
 use subprocess::Exec;
 
+/// Separator of the items in the byte stream of `Source::Stdin`, `Source::Exec` and `Source::File`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Separator {
+    /// The items are lines, the trailing `\r` is stripped as well.
+    Newline,
+    /// The items are terminated by NUL, e.g., the output of `fd -0` or `git ls-files -z`.
+    Null,
+}
+
+impl Separator {
+    #[inline]
+    pub fn byte(self) -> u8 {
+        match self {
+            Self::Newline => b'\n',
+            Self::Null => b'\0',
+        }
+    }
+
+    /// Strips the `\r` of line ending.
+    #[inline]
+    fn trim_end(self, line: &[u8]) -> &[u8] {
+        match (self, line.last()) {
+            (Self::Newline, Some(b'\r')) => &line[..line.len() - 1],
+            _ => line,
+        }
+    }
+
+    /// Strips the trailing separator of `item` read by `read_until`, if any.
+    #[inline]
+    pub fn trim_separator(self, item: &[u8]) -> &[u8] {
+        match item.split_last() {
+            Some((&last, rest)) if last == self.byte() => self.trim_end(rest),
+            _ => item,
+        }
+    }
+}
+
+/// Size of the chunks of a memory-mapped file scored in parallel.
+pub(crate) const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Returns true if `path` is under the cache dir of maple.
+fn is_cache_file(path: &Path) -> bool {
+    match (
+        path.canonicalize(),
+        utility::clap_cache_dir().canonicalize(),
+    ) {
+        (Ok(path), Ok(cache_dir)) => path.starts_with(cache_dir),
+        _ => false,
+    }
+}
+
+/// Memory-maps the cache file, returns `None` if the file is empty which can not be mapped, it's
+/// a compressed cache file or not a cache file at all, which should be read by
+/// [`utility::open_decompressed`] instead.
+pub(crate) fn mmap_file(path: &Path) -> Result<Option<Mmap>> {
+    // A mapped file truncated by others while being scanned raises SIGBUS, which is only ruled
+    // out for the cache files as they are written to a temp file and renamed into place, never
+    // modified afterwards. The other files, e.g., a log file still being written, are read by the
+    // buffered reader.
+    if !is_cache_file(path) {
+        return Ok(None);
+    }
+    let file = std::fs::File::open(path)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    let mmap = unsafe { Mmap::map(&file)? };
+    if utility::is_compressed(&mmap) {
+        return Ok(None);
+    }
+    Ok(Some(mmap))
+}
+
+/// Returns the items of `bytes` without the separators, same to `BufRead::lines()` for
+/// `Separator::Newline`.
+pub(crate) fn byte_lines(bytes: &[u8], separator: Separator) -> impl Iterator<Item = &[u8]> {
+    let mut remaining = bytes;
+    std::iter::from_fn(move || {
+        if remaining.is_empty() {
+            return None;
+        }
+        let line = match memchr::memchr(separator.byte(), remaining) {
+            Some(pos) => {
+                let line = &remaining[..pos];
+                remaining = &remaining[pos + 1..];
+                line
+            }
+            None => std::mem::take(&mut remaining),
+        };
+        Some(separator.trim_end(line))
+    })
+}
+
+/// Returns the items read from `reader` as bytes without the separators.
+///
+/// Unlike `BufRead::lines()`, the lines containing invalid UTF-8 are kept, stops at the first
+/// I/O error.
+pub fn read_byte_lines<R: BufRead>(
+    mut reader: R,
+    separator: Separator,
+) -> impl Iterator<Item = Vec<u8>> {
+    std::iter::from_fn(move || {
+        let mut line = Vec::new();
+        match reader.read_until(separator.byte(), &mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => {
+                let len = separator.trim_separator(&line).len();
+                line.truncate(len);
+                Some(line)
+            }
+        }
+    })
+}
+
+/// Splits `bytes` into the chunks of whole items, each one is about `chunk_size` bytes.
+pub(crate) fn line_chunks(bytes: &[u8], chunk_size: usize, separator: Separator) -> Vec<&[u8]> {
+    let mut chunks = Vec::with_capacity(bytes.len() / chunk_size + 1);
+    let mut remaining = bytes;
+    while remaining.len() > chunk_size {
+        match memchr::memchr(separator.byte(), &remaining[chunk_size..]) {
+            Some(pos) => {
+                let (chunk, rest) = remaining.split_at(chunk_size + pos + 1);
+                chunks.push(chunk);
+                remaining = rest;
+            }
+            None => break,
+        }
+    }
+    if !remaining.is_empty() {
+        chunks.push(remaining);
+    }
+    chunks
+}
+
+/// Decodes the line lossily, the valid UTF-8 line is borrowed without allocation.
+#[inline]
+pub(crate) fn decode_line(line: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(line)
+}
+
+/// Returns the filtered results of the items in `bytes`.
+///
+/// Only the matched items are allocated, the original bytes of the item are kept if it's not
+/// valid UTF-8.
+pub(crate) fn filter_bytes<F>(bytes: &[u8], separator: Separator, scorer: &F) -> Vec<FilterResult>
+where
+    F: Fn(&str) -> MatchResult,
+{
+    byte_lines(bytes, separator)
+        .filter_map(|line| {
+            let decoded = decode_line(line);
+            scorer(&decoded).map(|(score, indices)| {
+                let item = match decoded {
+                    Cow::Borrowed(raw) => raw.into(),
+                    Cow::Owned(_) => line.to_vec().into(),
+                };
+                (item, score, indices)
+            })
+        })
+        .collect()
+}
+
 /// Source is anything that can produce an iterator of String.
 #[derive(Debug)]
 pub enum Source<I: Iterator<Item = SourceItem>> {
-    Stdin,
+    Stdin(Separator),
     #[cfg(feature = "enable_dyn")]
-    Exec(Exec),
-    File(PathBuf),
+    Exec(Exec, Separator),
+    File(PathBuf, Separator),
     List(I),
 }
 
 impl<I: Iterator<Item = SourceItem>> From<PathBuf> for Source<I> {
     fn from(fpath: PathBuf) -> Self {
-        Self::File(fpath)
+        Self::File(fpath, Separator::Newline)
     }
 }
 
 #[cfg(feature = "enable_dyn")]
 impl<I: Iterator<Item = SourceItem>> From<Exec> for Source<I> {
     fn from(exec: Exec) -> Self {
-        Self::Exec(exec)
+        Self::Exec(exec, Separator::Newline)
     }
 }
 
@@ -32,39 +197,43 @@ impl<I: Iterator<Item = SourceItem>> Source<I> {
     /// matcher algo on each item in the input stream.
     ///
     /// This is kind of synchronous filtering, can be used for multi-staged processing.
-    pub fn filter(self, matcher: Matcher, query: &str) -> Result<Vec<FilterResult>> {
-        let do_match = |line: &str| matcher.do_match(&line.into(), query);
+    pub fn filter(self, matcher: &Matcher, query: &str) -> Result<Vec<FilterResult>> {
+        let do_match = |bytes: Vec<u8>| {
+            let item: SourceItem = bytes.into();
+            matcher
+                .do_match(&item, query)
+                .map(|(score, indices)| (item, score, indices))
+        };
 
         let filtered = match self {
-            Self::Stdin => std::io::stdin()
-                .lock()
-                .lines()
-                .filter_map(|lines_iter| {
-                    lines_iter.ok().and_then(|line| {
-                        do_match(&line).map(|(score, indices)| (line.into(), score, indices))
-                    })
-                })
+            Self::Stdin(separator) => read_byte_lines(std::io::stdin().lock(), separator)
+                .filter_map(do_match)
                 .collect::<Vec<_>>(),
             #[cfg(feature = "enable_dyn")]
-            Self::Exec(exec_cmd) => std::io::BufReader::new(exec_cmd.stream_stdout()?)
-                .lines()
-                .filter_map(|lines_iter| {
-                    lines_iter.ok().and_then(|line| {
-                        do_match(&line).map(|(score, indices)| (line.into(), score, indices))
-                    })
-                })
-                .collect::<Vec<_>>(),
-            Self::File(fpath) => std::fs::read_to_string(fpath)?
-                .par_lines()
-                .filter_map(|line| {
-                    do_match(&line)
-                        .map(|(score, indices)| (line.to_string().into(), score, indices))
-                })
-                .collect::<Vec<_>>(),
+            Self::Exec(exec_cmd, separator) => read_byte_lines(
+                std::io::BufReader::new(exec_cmd.stream_stdout()?),
+                separator,
+            )
+            .filter_map(do_match)
+            .collect::<Vec<_>>(),
+            Self::File(fpath, separator) => match mmap_file(&fpath)? {
+                Some(mmap) => {
+                    let do_match_line = |line: &str| matcher.do_match_line(line, query);
+                    line_chunks(&mmap, CHUNK_SIZE, separator)
+                        .into_par_iter()
+                        .flat_map(|chunk| filter_bytes(chunk, separator, &do_match_line))
+                        .collect::<Vec<_>>()
+                }
+                // Empty or compressed.
+                None => read_byte_lines(utility::open_decompressed(&fpath)?, separator)
+                    .filter_map(do_match)
+                    .collect::<Vec<_>>(),
+            },
             Self::List(list) => list
                 .filter_map(|item| {
-                    let line = item.match_text();
-                    do_match(line).map(|(score, indices)| (line.into(), score, indices))
+                    matcher
+                        .do_match(&item, query)
+                        .map(|(score, indices)| (item, score, indices))
                 })
                 .collect::<Vec<_>>(),
         };
@@ -72,3 +241,87 @@ impl<I: Iterator<Item = SourceItem>> Source<I> {
         Ok(filtered)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matcher::{Algo, Bonus, CaseMatching, MatchType};
+
+    #[test]
+    fn test_byte_lines() {
+        let lines =
+            |bytes: &'static [u8]| byte_lines(bytes, Separator::Newline).collect::<Vec<_>>();
+        assert_eq!(lines(b"a\nb\r\n\nc"), vec![&b"a"[..], b"b", b"", b"c"]);
+        assert_eq!(lines(b"a\n"), vec![&b"a"[..]]);
+        assert!(lines(b"").is_empty());
+
+        let items = byte_lines(b"a\nb\r\0c\0", Separator::Null).collect::<Vec<_>>();
+        assert_eq!(items, vec![&b"a\nb\r"[..], b"c"]);
+        let items = read_byte_lines(&b"a\nb\r\0c"[..], Separator::Null).collect::<Vec<_>>();
+        assert_eq!(items, vec![b"a\nb\r".to_vec(), b"c".to_vec()]);
+
+        assert_eq!(decode_line(b"caf\xe9"), "caf\u{FFFD}");
+    }
+
+    #[test]
+    fn test_invalid_utf8_lines() {
+        let input = &b"caf\xe9 latin1\r\nutf8 caf\xc3\xa9\n\xff"[..];
+        let lines = read_byte_lines(input, Separator::Newline).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![&b"caf\xe9 latin1"[..], b"utf8 caf\xc3\xa9", b"\xff"]
+        );
+
+        let scorer = |line: &str| {
+            if line.contains("caf") {
+                Some((0, vec![]))
+            } else {
+                None
+            }
+        };
+        let filtered = filter_bytes(input, Separator::Newline, &scorer);
+        assert_eq!(filtered.len(), 2);
+        assert_eq!(filtered[0].0.raw, "caf\u{FFFD} latin1");
+        assert_eq!(filtered[0].0.raw_bytes(), b"caf\xe9 latin1");
+        assert!(filtered[1].0.raw_bytes.is_none());
+        assert_eq!(filtered[1].0.raw_bytes(), "utf8 café".as_bytes());
+    }
+
+    #[test]
+    fn test_compressed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compressed");
+        let contents = b"src/lib.rs\nREADME.md\ncaf\xe9.rs\n";
+        std::fs::write(&path, utility::compress(contents).unwrap()).unwrap();
+
+        let matcher = Matcher::new(Algo::Fzy, MatchType::Full, Bonus::None, CaseMatching::Smart);
+        let source: Source<std::iter::Empty<_>> = Source::File(path.clone(), Separator::Newline);
+        let filtered = source.filter(&matcher, "rs").unwrap();
+        assert_eq!(filtered.len(), 2);
+        assert_eq!(filtered[1].0.raw_bytes(), b"caf\xe9.rs");
+    }
+
+    #[test]
+    fn test_non_cache_file_is_not_mapped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.log");
+        std::fs::write(&path, "src/lib.rs\nREADME.md\n").unwrap();
+        assert!(mmap_file(&path).unwrap().is_none());
+
+        let matcher = Matcher::new(Algo::Fzy, MatchType::Full, Bonus::None, CaseMatching::Smart);
+        let source: Source<std::iter::Empty<_>> = Source::File(path, Separator::Newline);
+        assert_eq!(source.filter(&matcher, "rs").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_line_chunks() {
+        let bytes = (0..1000)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>()
+            .into_bytes();
+        let chunks = line_chunks(&bytes, 100, Separator::Newline);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.last() == Some(&b'\n')));
+        assert_eq!(chunks.concat(), bytes);
+    }
+}
@@ -9,8 +9,10 @@ pub const DEFAULT_ICON: char = '';
 pub const FOLDER_ICON: char = '';
 pub const DEFAULT_FILER_ICON: char = '';
 
-// Each added icon length is 4 bytes.
-pub const ICON_LEN: usize = 4;
+/// Each added icon is one char followed by a space.
+///
+/// The matched indices are char indices, hence the offset of highlight is 2.
+pub const ICON_CHAR_LEN: usize = 2;
 
 /// The type used to represent icons.
 ///
@@ -62,11 +64,16 @@ pub fn prepend_filer_icon(path: &Path, line: &str) -> String {
     format!("{} {}", icon_for_filer(path), line)
 }
 
+/// Returns the icon for the kind of tag.
+pub fn tagkind_icon(kind: &str) -> Icon {
+    bsearch_icon_table(kind, TAGKIND_ICON_TABLE)
+        .map(|idx| TAGKIND_ICON_TABLE[idx].1)
+        .unwrap_or(DEFAULT_ICON)
+}
+
 fn get_tagkind_icon(line: &str) -> Icon {
     pattern::extract_proj_tags_kind(line)
-        .and_then(|kind| {
-            bsearch_icon_table(kind, TAGKIND_ICON_TABLE).map(|idx| TAGKIND_ICON_TABLE[idx].1)
-        })
+        .map(tagkind_icon)
         .unwrap_or(DEFAULT_ICON)
 }
 
@@ -102,6 +109,15 @@ impl IconPainter {
         }
     }
 
+    /// Returns appropriate icon given the typed path and kind of item instead of parsing the
+    /// text, `None` if the required one is missing.
+    pub fn get_icon_by_metadata(&self, path: Option<&Path>, kind: Option<&str>) -> Option<Icon> {
+        match *self {
+            Self::File | Self::Grep => path.map(|path| get_icon_or(path, DEFAULT_ICON)),
+            Self::ProjTags => kind.map(tagkind_icon),
+        }
+    }
+
     /// Returns appropriate icon for the given text.
     pub fn get_icon(&self, text: &str) -> Icon {
         match *self {
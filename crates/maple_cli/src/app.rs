@@ -1,5 +1,6 @@
 use anyhow::Result;
 use icon::IconPainter;
+use printer::OutputFormat;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
@@ -64,10 +65,32 @@ pub struct Maple {
     #[structopt(short, long, possible_values = &IconPainter::variants(), case_insensitive = true)]
     pub icon_painter: Option<IconPainter>,
 
+    /// Print the results of filter, exec and grep subcommands in the versioned output format.
+    ///
+    /// Intended for scripts and non-Vim clients, the schema version 1:
+    ///   - json: {"version": 1, "total": N, "items": [{"text": "..", "indices": [..]}]}.
+    ///   - jsonl: one {"version": 1, "text": "..", "indices": [..]} per line.
+    ///   - plain: the text of each item terminated by a newline.
+    ///   - null-separated: the text of each item terminated by a NUL.
+    #[structopt(long, possible_values = &OutputFormat::variants(), case_insensitive = true)]
+    pub format: Option<OutputFormat>,
+
     /// Do not use the cached file for exec subcommand.
     #[structopt(long = "no-cache")]
     pub no_cache: bool,
 
+    /// Directory of the cache files, overrides the env var CLAP_CACHE_DIR and the config file.
+    ///
+    /// Defaults to $XDG_CACHE_HOME/vim-clap.
+    #[structopt(long = "cache-dir", parse(from_os_str))]
+    pub cache_dir: Option<std::path::PathBuf>,
+
+    /// Kill the spawned commands running longer than this many seconds, 0 means no timeout.
+    ///
+    /// Overrides the env var CLAP_COMMAND_TIMEOUT.
+    #[structopt(long = "timeout", name = "SECS")]
+    pub timeout: Option<u64>,
+
     /// Enable the logging system.
     #[structopt(long = "log", parse(from_os_str))]
     pub log: Option<std::path::PathBuf>,
@@ -83,6 +106,13 @@ impl Maple {
         } else if let Ok(log_path) = std::env::var("VIM_CLAP_LOG_PATH") {
             crate::logger::init(log_path)?;
         }
+        // Also inherited by the maple processes refreshing the stale cache.
+        if let Some(ref cache_dir) = self.cache_dir {
+            std::env::set_var(utility::CLAP_CACHE_DIR_ENV, cache_dir);
+        }
+        if let Some(timeout) = self.timeout {
+            std::env::set_var(utility::CLAP_COMMAND_TIMEOUT_ENV, timeout.to_string());
+        }
         match self.command {
             Cmd::Version | Cmd::Upgrade(_) => unreachable!(),
             Cmd::Helptags(helptags) => helptags.run()?,
@@ -98,13 +128,19 @@ impl Maple {
             }
             Cmd::Cache(cache) => cache.run()?,
             Cmd::Filter(filter) => {
-                filter.run(self.number, self.winwidth, self.icon_painter)?;
+                filter.run(self.number, self.winwidth, self.icon_painter, self.format)?;
             }
             Cmd::Exec(exec) => {
-                exec.run(self.number, self.icon_painter, self.no_cache)?;
+                exec.run(self.number, self.icon_painter, self.no_cache, self.format)?;
             }
             Cmd::Grep(grep) => {
-                grep.run(self.number, self.winwidth, self.icon_painter, self.no_cache)?;
+                grep.run(
+                    self.number,
+                    self.winwidth,
+                    self.icon_painter,
+                    self.no_cache,
+                    self.format,
+                )?;
             }
         }
         Ok(())
@@ -1,8 +1,9 @@
 use anyhow::Result;
 use filter::{
-    matcher::{Bonus, MatchType},
+    matcher::{Algo, Bonus, CaseMatching, MatchType, Matcher},
     Source,
 };
+use source_item::{ItemMetadata, SourceItem};
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -27,15 +28,18 @@ impl Blines {
                 std::fs::read_to_string(&self.input)?
                     .lines()
                     .enumerate()
-                    .map(|(idx, item)| format!("{} {}", idx + 1, item))
-                    .map(Into::into),
+                    .map(|(idx, item)| {
+                        SourceItem::from(format!("{} {}", idx + 1, item)).with_metadata(
+                            ItemMetadata::default()
+                                .with_path(&self.input)
+                                .with_lnum(idx + 1),
+                        )
+                    }),
             ),
-            None,
+            Matcher::new(Algo::Fzy, MatchType::Full, Bonus::None, CaseMatching::Smart),
             number,
             winwidth,
             None,
-            MatchType::Full,
-            Bonus::None,
         )
     }
 }
@@ -0,0 +1,413 @@
+//! Manifest of the cache entries, which tracks the size and usage of each entry for the eviction.
+//!
+//! Each cache entry is a directory `cache_dir/{cache_key}` containing a single cache file named
+//! `{timestamp}_{total}_{bytes}B[_{fingerprint}]`, the manifest is keyed by the cache key and
+//! records the human-readable description of the cached command.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{read_dir, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use utility::{cache_key_env, is_cache_key};
+
+const MANIFEST_FILE: &str = "manifest.json";
+const MANIFEST_LOCK_FILE: &str = "manifest.lock";
+
+/// Returns the current unix timestamp in seconds.
+pub(super) fn now() -> u64 {
+    unix_secs(SystemTime::now())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the unix timestamp of the last use of `cache_file`, which is recorded as its mtime,
+/// see `CacheEntry::touch`.
+fn last_used_of(cache_file: &Path) -> Option<u64> {
+    std::fs::metadata(cache_file)
+        .and_then(|m| m.modified())
+        .ok()
+        .map(unix_secs)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Arguments of the cached command.
+    pub command: Vec<String>,
+    /// Working directory of the cached command, unknown for the entries created before the
+    /// manifest.
+    pub cwd: Option<PathBuf>,
+    /// Env vars included in the cache key.
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+    /// File name of the cache file.
+    pub file: String,
+    /// Number of the cached items.
+    pub total: usize,
+    /// Size of the cache file in bytes.
+    pub bytes: u64,
+    /// Unix timestamp in seconds when the cache file was created.
+    pub created: u64,
+    /// Unix timestamp in seconds when the cache file was used last time, synced from the mtime
+    /// of cache file on loading the manifest.
+    pub last_used: u64,
+}
+
+impl ManifestEntry {
+    /// Returns the human-readable description of the cached command.
+    pub fn description(&self) -> String {
+        if self.command.is_empty() {
+            "<unknown command>".into()
+        } else {
+            self.command.join(" ")
+        }
+    }
+
+    /// Constructs the entry of an untracked cache file from its name, the command is unknown.
+    fn from_cache_file(cache_file: &Path) -> Option<Self> {
+        let file = cache_file.file_name()?.to_str()?.to_string();
+        let super::CacheFileInfo {
+            timestamp: created,
+            total,
+            ..
+        } = super::CacheFileInfo::parse(&file)?;
+        Some(Self {
+            command: Vec::new(),
+            cwd: None,
+            env: Vec::new(),
+            file,
+            total,
+            bytes: std::fs::metadata(cache_file).ok()?.len(),
+            created,
+            last_used: last_used_of(cache_file).unwrap_or(created),
+        })
+    }
+}
+
+fn dir_paths(dir: &Path) -> Vec<PathBuf> {
+    read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns true if `file_name` is `{digits}_{digits}`.
+fn is_legacy_cache_file_name(file_name: &str) -> bool {
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let mut info = file_name.split('_');
+    matches!(
+        (info.next(), info.next(), info.next()),
+        (Some(timestamp), Some(total), None) if is_number(timestamp) && is_number(total)
+    )
+}
+
+/// Returns true if `dir` is exactly in the layout of cache before the stable cache key, i.e.,
+/// `arg1_arg2_arg3/{hash(cmd_dir)}/{timestamp}_{total}`, which is no longer reachable.
+///
+/// The cache dir is configurable, so nothing else is considered legacy to not remove the
+/// unrelated files of user.
+fn is_legacy_command_dir(dir: &Path) -> bool {
+    let entry_dirs = dir_paths(dir);
+    !entry_dirs.is_empty()
+        && entry_dirs.iter().all(|entry_dir| {
+            let is_hash = entry_dir
+                .file_name()
+                .and_then(|f| f.to_str())
+                .map(|f| f == "no_cmd_dir" || f.parse::<u64>().is_ok())
+                .unwrap_or(false);
+            let cache_files = dir_paths(entry_dir);
+            is_hash
+                && entry_dir.is_dir()
+                && !cache_files.is_empty()
+                && cache_files.iter().all(|f| {
+                    f.is_file()
+                        && f.file_name()
+                            .and_then(|f| f.to_str())
+                            .map(is_legacy_cache_file_name)
+                            .unwrap_or(false)
+                })
+        })
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(skip)]
+    root: PathBuf,
+    entries: BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+    /// Loads the manifest of cache dir `root`.
+    ///
+    /// The entries whose cache file no longer exists are dropped, the untracked cache files are
+    /// added, so that the manifest is always in sync with the cache dir.
+    pub fn load(root: &Path) -> Self {
+        let mut manifest = std::fs::read(root.join(MANIFEST_FILE))
+            .ok()
+            .and_then(|contents| serde_json::from_slice::<Self>(&contents).ok())
+            .unwrap_or_default();
+        manifest.root = root.to_path_buf();
+        manifest.reconcile();
+        manifest
+    }
+
+    /// Acquires the lock of manifest of cache dir `root`, which should be held from loading
+    /// the manifest until saving it back.
+    pub fn lock(root: &Path) -> Result<File> {
+        std::fs::create_dir_all(root)?;
+        super::lock_file(&root.join(MANIFEST_LOCK_FILE))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.root)?;
+        let path = self.root.join(MANIFEST_FILE);
+        super::write_atomic(&super::tmp_path(&path), &path, &serde_json::to_vec(self)?)
+    }
+
+    fn reconcile(&mut self) {
+        let root = self.root.clone();
+        self.entries.retain(
+            |key, entry| match last_used_of(&root.join(key).join(&entry.file)) {
+                Some(last_used) => {
+                    entry.last_used = last_used;
+                    true
+                }
+                None => false,
+            },
+        );
+
+        // root/cache_key.lock, the lock files left by the removed entries.
+        for lock_path in dir_paths(&root).into_iter().filter(|p| p.is_file()) {
+            let is_lock_of_key = lock_path
+                .file_stem()
+                .and_then(|f| f.to_str())
+                .map(is_cache_key)
+                .unwrap_or(false);
+            let entry_dir = lock_path.with_extension("");
+            if is_lock_of_key && !entry_dir.exists() {
+                super::CacheEntry::remove_lock_files(&entry_dir);
+            }
+        }
+
+        // root/cache_key/cache_file
+        for entry_dir in dir_paths(&root).into_iter().filter(|p| p.is_dir()) {
+            let key = match entry_dir.file_name().and_then(|f| f.to_str()) {
+                Some(key) if is_cache_key(key) => key.to_string(),
+                _ => {
+                    if is_legacy_command_dir(&entry_dir) {
+                        let _ = std::fs::remove_dir_all(&entry_dir);
+                    }
+                    continue;
+                }
+            };
+            if self.entries.contains_key(&key) {
+                continue;
+            }
+            if let Some(entry) = dir_paths(&entry_dir)
+                .iter()
+                .find_map(|f| ManifestEntry::from_cache_file(f))
+            {
+                self.entries.insert(key, entry);
+            }
+        }
+    }
+
+    fn key_of(&self, cache_file: &Path) -> Option<String> {
+        let entry_dir = cache_file.parent()?;
+        if entry_dir.parent()? != self.root {
+            return None;
+        }
+        entry_dir.file_name()?.to_str().map(Into::into)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &ManifestEntry)> {
+        self.entries.iter()
+    }
+
+    /// Returns the total size of cache files in bytes.
+    pub fn total_bytes(&self) -> u64 {
+        self.entries.values().map(|entry| entry.bytes).sum()
+    }
+
+    /// Adds the newly created `cache_file`, replacing the outdated one of the same entry.
+    pub fn record(
+        &mut self,
+        cache_file: &Path,
+        cmd_args: &[&str],
+        cmd_dir: Option<&Path>,
+        total: usize,
+    ) -> Result<()> {
+        let key = self
+            .key_of(cache_file)
+            .ok_or_else(|| anyhow::anyhow!("{} is not in the cache dir", cache_file.display()))?;
+        let now = now();
+        let entry = ManifestEntry {
+            command: cmd_args.iter().map(|x| x.to_string()).collect(),
+            cwd: cmd_dir.map(Path::to_path_buf),
+            env: cache_key_env(),
+            file: cache_file
+                .file_name()
+                .and_then(|f| f.to_str())
+                .map(Into::into)
+                .unwrap_or_default(),
+            total,
+            bytes: std::fs::metadata(cache_file)?.len(),
+            created: now,
+            last_used: now,
+        };
+        self.entries.insert(key, entry);
+        Ok(())
+    }
+
+    /// Removes the entries matched by `predicate` along with their cache files.
+    fn remove_where<F>(&mut self, mut predicate: F) -> Vec<ManifestEntry>
+    where
+        F: FnMut(&str, &ManifestEntry) -> bool,
+    {
+        let keys = self
+            .entries
+            .iter()
+            .filter(|(key, entry)| predicate(key, entry))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        keys.into_iter()
+            .filter_map(|key| {
+                if let Err(e) = super::CacheEntry::remove(&self.root.join(&key)) {
+                    log::error!("Failed to remove the cache entry {}: {:?}", key, e);
+                }
+                self.entries.remove(&key)
+            })
+            .collect()
+    }
+
+    /// Evicts the least recently used entries until the total size is no more than `max_bytes`.
+    ///
+    /// `keep` is never evicted, e.g., the cache file just created.
+    pub fn evict_lru(&mut self, max_bytes: u64, keep: Option<&Path>) -> Vec<ManifestEntry> {
+        let keep = keep.and_then(|f| self.key_of(f));
+        let mut lru = self
+            .entries
+            .iter()
+            .filter(|(key, _)| Some(*key) != keep.as_ref())
+            .map(|(key, entry)| (entry.last_used, key.clone(), entry.bytes))
+            .collect::<Vec<_>>();
+        lru.sort();
+
+        let mut total_bytes = self.total_bytes();
+        let mut evicted = Vec::new();
+        for (_, key, bytes) in lru {
+            if total_bytes <= max_bytes {
+                break;
+            }
+            total_bytes -= bytes;
+            evicted.push(key);
+        }
+        self.remove_where(|key, _| evicted.iter().any(|k| k == key))
+    }
+
+    /// Removes the entries whose cache file is not valid according to `is_valid`.
+    pub fn remove_invalid<F>(&mut self, mut is_valid: F) -> Vec<ManifestEntry>
+    where
+        F: FnMut(&Path, &ManifestEntry) -> bool,
+    {
+        let root = self.root.clone();
+        self.remove_where(|key, entry| !is_valid(&root.join(key).join(&entry.file), entry))
+    }
+
+    /// Removes the entries not used in the last `age`.
+    pub fn prune_older_than(&mut self, age: Duration) -> Vec<ManifestEntry> {
+        let deadline = now().saturating_sub(age.as_secs());
+        self.remove_where(|_, entry| entry.last_used < deadline)
+    }
+
+    /// Removes the entries of the commands executed in `project_dir`.
+    pub fn remove_project(&mut self, project_dir: &Path) -> Vec<ManifestEntry> {
+        let canonical = project_dir.canonicalize().ok();
+        self.remove_where(|_, entry| match entry.cwd {
+            Some(ref cwd) => cwd == project_dir || Some(cwd) == canonical.as_ref(),
+            None => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evict_lru() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let project = PathBuf::from("/tmp/project");
+        let create = |args: &[&str], last_used: u64, bytes: usize, manifest: &mut Manifest| {
+            let entry_dir = root.join(utility::cache_key(args, Some(&project)));
+            std::fs::create_dir_all(&entry_dir).unwrap();
+            let cache_file = entry_dir.join(format!("1600000000_{}", bytes));
+            std::fs::write(&cache_file, vec![b'\n'; bytes]).unwrap();
+            manifest
+                .record(&cache_file, args, Some(&project), bytes)
+                .unwrap();
+            // The last used time is the mtime of cache file.
+            File::options()
+                .write(true)
+                .open(&cache_file)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(last_used))
+                .unwrap();
+            cache_file
+        };
+
+        // The legacy layout is removed.
+        let legacy_dir = root.join("rg_--files").join("123");
+        std::fs::create_dir_all(&legacy_dir).unwrap();
+        std::fs::write(legacy_dir.join("1600000000_1"), "a\n").unwrap();
+        std::fs::create_dir_all(root.join("unrelated")).unwrap();
+        // Not exactly in the legacy layout.
+        let unrelated_dir = root.join("notes").join("123");
+        std::fs::create_dir_all(&unrelated_dir).unwrap();
+        std::fs::write(unrelated_dir.join("1600000000_1_draft"), "a\n").unwrap();
+
+        let mut manifest = Manifest::load(root);
+        assert!(!root.join("rg_--files").exists());
+        assert!(root.join("unrelated").exists());
+        assert!(unrelated_dir.exists());
+        create(&["fd", "--type", "f"], 3, 100, &mut manifest);
+        let oldest = create(&["rg", "--files"], 1, 100, &mut manifest);
+        create(&["git", "ls-files"], 2, 100, &mut manifest);
+        manifest.save().unwrap();
+
+        let mut manifest = Manifest::load(root);
+        assert_eq!(manifest.total_bytes(), 300);
+
+        // The kept entry is never evicted even it's the least recently used one.
+        let evicted = manifest.evict_lru(200, Some(&oldest));
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].command, vec!["git", "ls-files"]);
+        assert!(oldest.exists());
+
+        // The lock files are removed along with the entry.
+        let oldest_lock = oldest.parent().unwrap().with_extension("lock");
+        std::fs::write(&oldest_lock, "").unwrap();
+        let evicted = manifest.evict_lru(100, None);
+        assert_eq!(evicted[0].command, vec!["rg", "--files"]);
+        assert!(!oldest.exists());
+        assert!(!oldest_lock.exists());
+
+        // The orphan lock files are removed on loading.
+        let orphan_lock = root.join(format!("{}.refresh", "0".repeat(16)));
+        std::fs::write(&orphan_lock, "").unwrap();
+        let mut manifest = Manifest::load(root);
+        assert!(!orphan_lock.exists());
+
+        assert_eq!(manifest.remove_project(&project).len(), 1);
+        assert_eq!(Manifest::load(root).total_bytes(), 0);
+    }
+}
@@ -0,0 +1,938 @@
+use anyhow::{anyhow, Result};
+use filter::Separator;
+use fs2::FileExt;
+use icon::IconPainter;
+use std::fs::{read_dir, DirEntry, File, OpenOptions};
+use std::io::{BufRead, BufWriter, Read, Write};
+use std::path::{self, Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use structopt::StructOpt;
+use utility::{
+    cache_key, clap_cache_dir, compression_enabled, config_file, dir_fingerprint, get_cached_entry,
+    open_decompressed, remove_dir_contents, CacheWriter, CommandError,
+};
+
+mod manifest;
+
+pub use manifest::{Manifest, ManifestEntry};
+
+/// Default upper bound of the total size of cache files.
+const DEFAULT_MAX_CACHE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Minimum interval of updating the last used time of a cache entry.
+const TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Extension of the lock file next to the entry directory serializing the writers and removers.
+const LOCK_EXTENSION: &str = "lock";
+
+/// Extension of the lock file next to the entry directory held by the refreshing process.
+const REFRESH_LOCK_EXTENSION: &str = "refresh";
+
+/// Returns the upper bound of the total size of cache files, which can be configured by the
+/// env var `CLAP_CACHE_MAX_SIZE`, e.g., `CLAP_CACHE_MAX_SIZE=512M`.
+pub fn max_cache_size() -> u64 {
+    std::env::var("CLAP_CACHE_MAX_SIZE")
+        .ok()
+        .and_then(|size| parse_size(&size).ok())
+        .unwrap_or(DEFAULT_MAX_CACHE_SIZE)
+}
+
+/// Parses the size in bytes with an optional unit `K`, `M` or `G`, e.g., `512M`.
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (number, unit) = match s.char_indices().last() {
+        Some((idx, c)) if c.is_ascii_alphabetic() => (&s[..idx], c.to_ascii_uppercase()),
+        _ => (s, 'B'),
+    };
+    let unit = match unit {
+        'B' => 1,
+        'K' => 1024,
+        'M' => 1024 * 1024,
+        'G' => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!("Invalid size: {}, expected e.g. 512M or 1G", s)),
+    };
+    Ok(number.trim().parse::<u64>()? * unit)
+}
+
+/// Parses the duration with a unit `m`, `h` or `d`, e.g., `7d`.
+fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let unit = match s.chars().last() {
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        _ => {
+            return Err(anyhow!(
+                "Invalid duration: {}, expected e.g. 30m, 12h or 7d",
+                s
+            ))
+        }
+    };
+    Ok(Duration::from_secs(s[..s.len() - 1].parse::<u64>()? * unit))
+}
+
+fn format_size(bytes: u64) -> String {
+    const KB: u64 = 1024;
+    const MB: u64 = 1024 * KB;
+    const GB: u64 = 1024 * MB;
+    match bytes {
+        b if b >= GB => format!("{:.1}G", b as f64 / GB as f64),
+        b if b >= MB => format!("{:.1}M", b as f64 / MB as f64),
+        b if b >= KB => format!("{:.1}K", b as f64 / KB as f64),
+        b => format!("{}B", b),
+    }
+}
+
+fn format_age(timestamp: u64) -> String {
+    match manifest::now().saturating_sub(timestamp) {
+        secs if secs >= 24 * 60 * 60 => format!("{}d ago", secs / (24 * 60 * 60)),
+        secs if secs >= 60 * 60 => format!("{}h ago", secs / (60 * 60)),
+        secs => format!("{}m ago", secs / 60),
+    }
+}
+
+fn print_removed(removed: &[ManifestEntry]) {
+    for entry in removed {
+        println!(
+            "Removed {} ({})",
+            entry.description(),
+            format_size(entry.bytes)
+        );
+    }
+    println!(
+        "{} entries removed, {} freed",
+        removed.len(),
+        format_size(removed.iter().map(|e| e.bytes).sum())
+    );
+}
+
+/// Returns true if the cache file is complete and contains `total` items.
+///
+/// The items of cache file are separated by either newline or NUL, see `--read0`.
+fn verify(cache_file: &Path, total: usize) -> bool {
+    let size_matches = match CacheFileInfo::of(cache_file).and_then(|info| info.bytes) {
+        Some(bytes) => matches!(std::fs::metadata(cache_file), Ok(m) if m.len() == bytes),
+        None => true,
+    };
+    size_matches
+        && [Separator::Newline, Separator::Null]
+            .iter()
+            .any(|&separator| {
+                CacheEntry::count_matches(cache_file, total, separator).unwrap_or(false)
+            })
+}
+
+/// Acquires the exclusive lock on the file `path`, which is released once the returned file is
+/// dropped.
+pub(crate) fn lock_file(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)?;
+    file.lock_exclusive()?;
+    Ok(file)
+}
+
+/// Acquires the exclusive lock on the file `path` without blocking, returns `None` if it's
+/// held by another process.
+pub(crate) fn try_lock_file(path: &Path) -> Result<Option<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the path of temp file used for writing `path` atomically, which is a hidden sibling
+/// of `path` unique to each call, so that the concurrent writers of the same `path`, even in
+/// the same process, never share a temp file.
+pub(crate) fn tmp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let file_name = path
+        .file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        file_name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// File written to a temp file by streaming, which is renamed to its final path by
+/// [`AtomicFile::persist`], so that the final path is either absent or complete even if the
+/// write is interrupted. The temp file is removed if it's dropped without being persisted.
+pub struct AtomicFile {
+    tmp: PathBuf,
+    /// Always `Some` until it's persisted.
+    writer: Option<CacheWriter<BufWriter<File>>>,
+}
+
+impl AtomicFile {
+    /// Creates the temp file `tmp`, the contents are compressed if `compress` is true.
+    pub fn create(tmp: PathBuf, compress: bool) -> Result<Self> {
+        let file = File::create(&tmp)?;
+        Ok(Self {
+            tmp,
+            writer: Some(CacheWriter::new(BufWriter::new(file), compress)),
+        })
+    }
+
+    /// Completes the temp file, returns its size in bytes. Nothing can be written afterwards.
+    pub fn finish(&mut self) -> Result<u64> {
+        if let Some(writer) = self.writer.take() {
+            let mut writer = writer.finish()?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        Ok(std::fs::metadata(&self.tmp)?.len())
+    }
+
+    /// Returns the path of temp file, which is complete after [`Self::finish`].
+    pub fn tmp(&self) -> &Path {
+        &self.tmp
+    }
+
+    /// Completes the temp file and renames it to `path`.
+    pub fn persist(mut self, path: &Path) -> Result<()> {
+        self.finish()?;
+        std::fs::rename(&self.tmp, path)?;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.writer {
+            Some(ref mut writer) => writer.write(buf),
+            None => Ok(0),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.writer {
+            Some(ref mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        // The temp file is gone if it has been persisted, otherwise it's incomplete.
+        let _ = std::fs::remove_file(&self.tmp);
+    }
+}
+
+/// Writes `contents` to the temp file `tmp` and then renames it to `path`, see [`AtomicFile`].
+pub(crate) fn write_atomic(tmp: &Path, path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = AtomicFile::create(tmp.to_path_buf(), false)?;
+    file.write_all(contents)?;
+    file.persist(path)
+}
+
+/// Loads the manifest of cache dir, updates it with `f` and saves it back.
+///
+/// The failure of maintaining the manifest is only logged as it does not affect the cache itself.
+fn update_manifest(f: impl FnOnce(&mut Manifest)) {
+    let update = || -> Result<()> {
+        let _lock = Manifest::lock(&clap_cache_dir())?;
+        let mut manifest = Manifest::load(&clap_cache_dir());
+        f(&mut manifest);
+        manifest.save()
+    };
+    if let Err(e) = update() {
+        log::error!("Failed to update the cache manifest: {:?}", e);
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+enum CacheCommand {
+    /// Show the total size and the entries of cache.
+    Stats,
+    /// Remove the cache entries not used recently, or exceeding the size cap, at least one of
+    /// the options is required.
+    Prune {
+        /// Remove the entries not used in the given duration, e.g., 30m, 12h or 7d.
+        #[structopt(long, parse(try_from_str = parse_duration))]
+        older_than: Option<Duration>,
+
+        /// Evict the least recently used entries until the total size is within this size,
+        /// e.g., 512M. The default cap is the env var CLAP_CACHE_MAX_SIZE or 1G.
+        #[structopt(long, parse(try_from_str = parse_size))]
+        max_size: Option<u64>,
+    },
+    /// Remove the cache entries of a project.
+    Remove {
+        /// Working directory of the cached commands.
+        #[structopt(index = 1, parse(from_os_str))]
+        dir: PathBuf,
+    },
+    /// Check every cache file thoroughly by reading it through and counting its items, the
+    /// invalid ones are removed.
+    Verify,
+}
+
+/// List and remove all the cached contents.
+#[derive(StructOpt, Debug, Clone)]
+pub struct Cache {
+    /// List the current cached entries.
+    #[structopt(short, long)]
+    list: bool,
+
+    /// Purge all the cached contents.
+    #[structopt(short, long)]
+    purge: bool,
+
+    #[structopt(subcommand)]
+    cmd: Option<CacheCommand>,
+}
+
+impl Cache {
+    pub fn run(&self) -> Result<()> {
+        let cache_dir = clap_cache_dir();
+        if self.purge {
+            remove_dir_contents(&cache_dir)?;
+            println!(
+                "Current cache directory {} has been purged",
+                cache_dir.display()
+            );
+            return Ok(());
+        }
+        if self.list {
+            self.list(&cache_dir)?;
+        }
+        match self.cmd {
+            Some(CacheCommand::Stats) => Self::stats(&cache_dir),
+            Some(CacheCommand::Prune {
+                older_than,
+                max_size,
+            }) => {
+                if older_than.is_none() && max_size.is_none() {
+                    return Err(anyhow!(
+                        "Specify --older-than and/or --max-size, e.g., --older-than 7d"
+                    ));
+                }
+                let _lock = Manifest::lock(&cache_dir)?;
+                let mut manifest = Manifest::load(&cache_dir);
+                let mut removed = match older_than {
+                    Some(age) => manifest.prune_older_than(age),
+                    None => Vec::new(),
+                };
+                if let Some(max_size) = max_size {
+                    removed.extend(manifest.evict_lru(max_size, None));
+                }
+                manifest.save()?;
+                print_removed(&removed);
+            }
+            Some(CacheCommand::Remove { ref dir }) => {
+                let _lock = Manifest::lock(&cache_dir)?;
+                let mut manifest = Manifest::load(&cache_dir);
+                let removed = manifest.remove_project(dir);
+                manifest.save()?;
+                print_removed(&removed);
+            }
+            Some(CacheCommand::Verify) => {
+                let _lock = Manifest::lock(&cache_dir)?;
+                let mut manifest = Manifest::load(&cache_dir);
+                let removed =
+                    manifest.remove_invalid(|cache_file, entry| verify(cache_file, entry.total));
+                manifest.save()?;
+                print_removed(&removed);
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn stats(cache_dir: &Path) {
+        let manifest = Manifest::load(cache_dir);
+        let mut entries = manifest.entries().map(|(_, e)| e).collect::<Vec<_>>();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.last_used));
+        println!("Cache directory: {}", cache_dir.display());
+        if let Some(config_file) = config_file().filter(|f| f.exists()) {
+            println!("Config file: {}", config_file.display());
+        }
+        println!(
+            "Total size: {} / {}",
+            format_size(manifest.total_bytes()),
+            format_size(max_cache_size())
+        );
+        println!("Entries: {}\n", entries.len());
+        for entry in entries {
+            println!(
+                "{:>8}  {:>9} items  used {:>8}  {}  {}",
+                format_size(entry.bytes),
+                entry.total,
+                format_age(entry.last_used),
+                entry
+                    .cwd
+                    .as_ref()
+                    .map(|cwd| cwd.display().to_string())
+                    .unwrap_or_else(|| "<unknown>".into()),
+                entry.description()
+            );
+        }
+    }
+
+    fn list(&self, cache_dir: &PathBuf) -> Result<()> {
+        let cache_dir_str = cache_dir.display();
+        println!("Current cache directory:");
+        println!("\t{}\n", cache_dir_str);
+        if self.list {
+            println!("Cached entries:");
+            let mut entries = read_dir(cache_dir)?
+                .map(|res| {
+                    res.map(|e| {
+                        e.path()
+                            .file_name()
+                            .and_then(std::ffi::OsStr::to_str)
+                            .map(Into::into)
+                            .expect("Couldn't get file name")
+                    })
+                })
+                .collect::<Result<Vec<String>, std::io::Error>>()?;
+
+            entries.sort();
+
+            for fname in entries {
+                println!("\t{}{}{}", cache_dir_str, path::MAIN_SEPARATOR, fname);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Info recorded in the name of cache file, i.e., `{timestamp}_{total}_{bytes}B[_{fingerprint}]`.
+///
+/// The size is absent in the name of legacy cache file `{timestamp}_{total}[_{fingerprint}]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheFileInfo {
+    /// Unix timestamp in seconds when the cache file was created.
+    pub timestamp: u64,
+    /// Number of the cached items.
+    pub total: usize,
+    /// Size of the cache file in bytes.
+    pub bytes: Option<u64>,
+    /// Fingerprint of the working directory of the cached command, see [`dir_fingerprint`].
+    pub fingerprint: Option<u64>,
+}
+
+impl CacheFileInfo {
+    pub fn parse(file_name: &str) -> Option<Self> {
+        let mut info = file_name.split('_');
+        let timestamp = info.next()?.parse().ok()?;
+        let total = info.next()?.parse().ok()?;
+        let mut next = info.next();
+        let bytes = match next.and_then(|s| s.strip_suffix('B')) {
+            Some(bytes) => {
+                next = info.next();
+                Some(bytes.parse().ok()?)
+            }
+            None => None,
+        };
+        let fingerprint = match next {
+            Some(fingerprint) => Some(fingerprint.parse().ok()?),
+            None => None,
+        };
+        if info.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            timestamp,
+            total,
+            bytes,
+            fingerprint,
+        })
+    }
+
+    /// Parses the file name of cache file `path`.
+    pub fn of(path: &Path) -> Option<Self> {
+        path.file_name()?.to_str().and_then(Self::parse)
+    }
+
+    fn file_name(&self) -> String {
+        let mut file_name = format!("{}_{}", self.timestamp, self.total);
+        if let Some(bytes) = self.bytes {
+            file_name.push_str(&format!("_{}B", bytes));
+        }
+        if let Some(fingerprint) = self.fingerprint {
+            file_name.push_str(&format!("_{}", fingerprint));
+        }
+        file_name
+    }
+}
+
+pub struct CacheEntry;
+
+impl CacheEntry {
+    /// Returns the directory of cache entry given command arguments and its working directory.
+    pub fn dir(cmd_args: &[&str], cmd_dir: Option<&Path>) -> PathBuf {
+        clap_cache_dir().join(cache_key(cmd_args, cmd_dir))
+    }
+
+    /// Acquires the lock of entry directory `entry_dir`, which serializes the writers and the
+    /// removers of cache files of the same cache key.
+    fn lock(entry_dir: &Path) -> Result<File> {
+        lock_file(&entry_dir.with_extension(LOCK_EXTENSION))
+    }
+
+    /// Removes the entry directory `entry_dir` along with its lock files.
+    ///
+    /// A lock file is only removed if no one holds it, the process still waiting for it would be
+    /// left with an unlinked file, which is harmless as the cache files are always written to a
+    /// temp file first and renamed into place.
+    pub(crate) fn remove(entry_dir: &Path) -> std::io::Result<()> {
+        Self::remove_lock_files(entry_dir);
+        std::fs::remove_dir_all(entry_dir)
+    }
+
+    /// Removes the lock files of the entry directory `entry_dir` which are not held by others.
+    pub(crate) fn remove_lock_files(entry_dir: &Path) {
+        for extension in &[LOCK_EXTENSION, REFRESH_LOCK_EXTENSION] {
+            let lock_path = entry_dir.with_extension(extension);
+            if !lock_path.exists() {
+                continue;
+            }
+            if let Ok(Some(_lock)) = try_lock_file(&lock_path) {
+                let _ = std::fs::remove_file(&lock_path);
+            }
+        }
+    }
+
+    /// Starts writing a cache file of the entry directory `entry_dir` by streaming, which is
+    /// compressed if enabled.
+    ///
+    /// The contents are written to a temp file outside of `entry_dir` until [`Self::persist`],
+    /// so that the readers never see a partially written cache file.
+    pub fn create_writer(entry_dir: &Path) -> Result<AtomicFile> {
+        std::fs::create_dir_all(entry_dir)?;
+        AtomicFile::create(tmp_path(entry_dir), compression_enabled())
+    }
+
+    /// Completes the cache file written by streaming and moves it into `entry_dir`, returns
+    /// its final path.
+    ///
+    /// The `total` info, the size of cache file and the `fingerprint` of working directory are
+    /// recorded in the file name. The outdated cache file in `entry_dir` is removed.
+    pub fn persist(
+        mut file: AtomicFile,
+        entry_dir: &Path,
+        total: usize,
+        fingerprint: Option<u64>,
+    ) -> Result<PathBuf> {
+        let info = CacheFileInfo {
+            timestamp: manifest::now(),
+            total,
+            bytes: Some(file.finish()?),
+            fingerprint,
+        };
+        let entry = entry_dir.join(info.file_name());
+        std::fs::create_dir_all(entry_dir)?;
+
+        let _lock = Self::lock(entry_dir)?;
+
+        file.persist(&entry)?;
+
+        // Remove the other outdated cache file if there are any.
+        //
+        // There should be only one cache file in entry_dir at this moment.
+        for old_entry in read_dir(entry_dir)?.filter_map(|e| e.ok()) {
+            let path = old_entry.path();
+            if path != entry {
+                if path.is_dir() {
+                    std::fs::remove_dir_all(path)?;
+                } else {
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+
+        Ok(entry)
+    }
+
+    /// Writes the `contents` to a new cache file of `entry_dir`, see [`Self::create_writer`]
+    /// and [`Self::persist`].
+    pub fn write<T: AsRef<[u8]>>(
+        entry_dir: &Path,
+        total: usize,
+        fingerprint: Option<u64>,
+        contents: T,
+    ) -> Result<PathBuf> {
+        let mut file = Self::create_writer(entry_dir)?;
+        file.write_all(contents.as_ref())?;
+        Self::persist(file, entry_dir, total, fingerprint)
+    }
+
+    /// Returns true if the number of items in the cache file `entry` matches `total`, with the
+    /// items separated by `separator`.
+    ///
+    /// The whole cache file is read, which is only done by [`Self::validate`] for the legacy
+    /// cache file and by `maple cache verify`.
+    pub fn count_matches(
+        entry: &Path,
+        total: usize,
+        separator: Separator,
+    ) -> std::io::Result<bool> {
+        let mut file = open_decompressed(entry)?;
+        let mut buf = vec![0u8; 64 * 1024];
+        let (mut count, mut last_byte) = (0usize, None);
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            count += bytecount::count(&buf[..n], separator.byte());
+            last_byte = Some(buf[n - 1]);
+        }
+        // The last item may be unterminated.
+        let unterminated = matches!(last_byte, Some(b) if b != separator.byte());
+        Ok(count == total || (unterminated && count + 1 == total))
+    }
+
+    /// Returns true if the cache file `entry` is complete, i.e., its size matches the one
+    /// recorded in its name, which is cheap enough for every cache hit.
+    ///
+    /// The legacy cache file without the recorded size is checked by counting its items. The
+    /// invalid cache file is removed.
+    pub fn validate(entry: &Path, total: usize, separator: Separator) -> bool {
+        let is_valid = match CacheFileInfo::of(entry).and_then(|info| info.bytes) {
+            Some(bytes) => std::fs::metadata(entry).map(|m| m.len() == bytes),
+            None => Self::count_matches(entry, total, separator),
+        };
+        match is_valid {
+            Ok(true) => true,
+            Ok(false) => {
+                log::error!(
+                    "Removing the invalid cache file {}, expected {} items",
+                    entry.display(),
+                    total
+                );
+                Self::remove_invalid(entry);
+                false
+            }
+            // E.g., it has been replaced by a newer cache file.
+            Err(e) => {
+                log::debug!("Failed to validate {}: {:?}", entry.display(), e);
+                false
+            }
+        }
+    }
+
+    /// Removes the invalid cache file `entry` under the lock of its entry directory, so that
+    /// the cache file being persisted concurrently is never removed.
+    fn remove_invalid(entry: &Path) {
+        let remove = || -> Result<()> {
+            let entry_dir = entry
+                .parent()
+                .ok_or_else(|| anyhow!("Invalid cache entry: {}", entry.display()))?;
+            let _lock = Self::lock(entry_dir)?;
+            match std::fs::remove_file(entry) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        };
+        if let Err(e) = remove() {
+            log::error!("Failed to remove {}: {:?}", entry.display(), e);
+        }
+    }
+
+    /// Creates a new cache entry.
+    pub fn create<T: AsRef<[u8]>>(
+        cmd_args: &[&str],
+        cmd_dir: Option<PathBuf>,
+        total: usize,
+        contents: T,
+    ) -> Result<PathBuf> {
+        let entry_dir = Self::dir(cmd_args, cmd_dir.as_deref());
+        let fingerprint = cmd_dir.as_deref().and_then(dir_fingerprint);
+        let entry = Self::write(&entry_dir, total, fingerprint, contents)?;
+        Self::record(&entry, cmd_args, cmd_dir.as_deref(), total);
+        Ok(entry)
+    }
+
+    /// Adds the newly written cache entry to the manifest, then evicts the least recently used
+    /// entries if the total size of cache exceeds the limit.
+    pub fn record(entry: &Path, cmd_args: &[&str], cmd_dir: Option<&Path>, total: usize) {
+        update_manifest(|manifest| {
+            if let Err(e) = manifest.record(entry, cmd_args, cmd_dir, total) {
+                log::error!("Failed to record the cache entry: {:?}", e);
+            }
+            manifest.evict_lru(max_cache_size(), Some(entry));
+        })
+    }
+
+    /// Marks the cache entry as used just now.
+    ///
+    /// The last used time is recorded as the mtime of cache file instead of in the manifest, so
+    /// that a cache hit doesn't rewrite the manifest, and it's updated at most once per
+    /// [`TOUCH_INTERVAL`].
+    pub fn touch(entry: &Path) {
+        let touch = || -> std::io::Result<()> {
+            let modified = std::fs::metadata(entry)?.modified()?;
+            let used_recently = SystemTime::now()
+                .duration_since(modified)
+                .map(|elapsed| elapsed < TOUCH_INTERVAL)
+                .unwrap_or(true);
+            if !used_recently {
+                OpenOptions::new()
+                    .write(true)
+                    .open(entry)?
+                    .set_modified(SystemTime::now())?;
+            }
+            Ok(())
+        };
+        if let Err(e) = touch() {
+            log::debug!(
+                "Failed to touch the cache entry {}: {:?}",
+                entry.display(),
+                e
+            );
+        }
+    }
+
+    /// Get the total number of this cache entry from its file name.
+    pub fn get_total(cached_entry: &DirEntry) -> Result<usize> {
+        CacheFileInfo::of(&cached_entry.path())
+            .map(|info| info.total)
+            .ok_or_else(|| anyhow!("Invalid cache entry name: {:?}", cached_entry.file_name()))
+    }
+}
+
+/// Returns true if the contents of `cmd_dir` have changed since the cache was created.
+///
+/// The cache created without a fingerprint is always stale.
+pub fn is_stale(cache: &Path, cmd_dir: &Path) -> bool {
+    match (
+        CacheFileInfo::of(cache).and_then(|info| info.fingerprint),
+        dir_fingerprint(cmd_dir),
+    ) {
+        (Some(recorded), Some(current)) => recorded != current,
+        (None, Some(_)) => true,
+        (_, None) => false,
+    }
+}
+
+/// Regenerates the cache in a detached maple process with `--no-cache` if it's stale, so that
+/// the stale result can still be served immediately.
+///
+/// At most one process refreshes the same cache entry at a time, which holds the lock file
+/// `{cache_key}.refresh` next to the entry directory.
+///
+/// Returns true if the cache is stale.
+pub fn revalidate_cache(cache: &Path, cmd_dir: &Path) -> bool {
+    if !is_stale(cache, cmd_dir) {
+        return false;
+    }
+    let refresh = || -> Result<()> {
+        let entry_dir = cache
+            .parent()
+            .ok_or_else(|| anyhow!("Invalid cache entry: {}", cache.display()))?;
+        let lock = match try_lock_file(&entry_dir.with_extension(REFRESH_LOCK_EXTENSION))? {
+            Some(lock) => lock,
+            None => {
+                log::debug!("{} is being refreshed", cache.display());
+                return Ok(());
+            }
+        };
+        let mut args = std::env::args_os().skip(1).collect::<Vec<_>>();
+        args.insert(0, "--no-cache".into());
+        std::process::Command::new(std::env::current_exe()?)
+            .args(args)
+            // The locked file is inherited as stdin, so that the lock is held until the
+            // refreshing process exits.
+            .stdin(Stdio::from(lock))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        Ok(())
+    };
+    if let Err(e) = refresh() {
+        log::error!(
+            "Failed to refresh the stale cache {}: {:?}",
+            cache.display(),
+            e
+        );
+    }
+    true
+}
+
+#[derive(Debug, Clone)]
+pub enum SendResponse {
+    Json,
+    JsonWithContentLength,
+}
+
+/// Reads the first lines from cache file and send back the cached info, along with the
+/// `warning` of the command which has created the cache if any.
+pub fn send_response_from_cache(
+    tempfile: &PathBuf,
+    total: usize,
+    stale: bool,
+    warning: Option<&CommandError>,
+    response_ty: SendResponse,
+    icon_painter: Option<IconPainter>,
+) {
+    let using_cache = true;
+    let lines_iter =
+        open_decompressed(tempfile).map(|reader| reader.lines().map_while(Result::ok).take(100));
+    if let Ok(lines_iter) = lines_iter {
+        let lines: Vec<String> = if let Some(painter) = icon_painter {
+            lines_iter.map(|x| painter.paint(&x)).collect()
+        } else {
+            lines_iter.collect()
+        };
+        match response_ty {
+            SendResponse::Json => {
+                println_json!(total, tempfile, using_cache, stale, warning, lines)
+            }
+            SendResponse::JsonWithContentLength => {
+                print_json_with_length!(total, tempfile, using_cache, stale, warning, lines)
+            }
+        }
+    } else {
+        match response_ty {
+            SendResponse::Json => println_json!(total, tempfile, using_cache, stale, warning),
+            SendResponse::JsonWithContentLength => {
+                print_json_with_length!(total, tempfile, using_cache, stale, warning)
+            }
+        }
+    }
+}
+
+/// Returns the cache file path and number of total cached items.
+pub fn cache_exists(args: &[&str], cmd_dir: &PathBuf) -> Result<(PathBuf, usize)> {
+    if let Ok(cached_entry) = get_cached_entry(args, cmd_dir) {
+        if let Ok(total) = CacheEntry::get_total(&cached_entry) {
+            let tempfile = cached_entry.path();
+            if CacheEntry::validate(&tempfile, total, Separator::Newline) {
+                CacheEntry::touch(&tempfile);
+                return Ok((tempfile, total));
+            }
+        }
+    }
+    Err(anyhow!(
+        "Cache does not exist for: {:?} in {:?}",
+        args,
+        cmd_dir
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_stale() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let nested = dir.join("src").join("cmd");
+        std::fs::create_dir_all(&nested).unwrap();
+
+        let fingerprint = dir_fingerprint(dir).unwrap();
+        let fresh = dir.join(format!("1600000000_100_{}", fingerprint));
+        assert!(!is_stale(&fresh, dir));
+
+        // The legacy cache without fingerprint.
+        assert!(is_stale(&dir.join("1600000000_100"), dir));
+        assert!(is_stale(&dir.join("1600000000_100_0"), dir));
+
+        // The change in the nested directory is detected.
+        std::fs::write(nested.join("new.rs"), "").unwrap();
+        assert!(is_stale(&fresh, dir));
+    }
+
+    #[test]
+    fn test_try_lock_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lock_path = tmp.path().join("12345.refresh");
+        let lock = try_lock_file(&lock_path).unwrap();
+        assert!(lock.is_some());
+        assert!(try_lock_file(&lock_path).unwrap().is_none());
+        drop(lock);
+        assert!(try_lock_file(&lock_path).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_write_and_validate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("12345");
+        let old_entry = CacheEntry::write(&dir, 2, None, "a\nb\n").unwrap();
+        let entry = CacheEntry::write(&dir, 3, Some(42), "a\nb\nc").unwrap();
+        let info = CacheFileInfo::of(&entry).unwrap();
+        assert_eq!((info.total, info.fingerprint), (3, Some(42)));
+        assert_eq!(info.bytes, Some(std::fs::metadata(&entry).unwrap().len()));
+
+        // The outdated entry is replaced and no temp file is left.
+        assert!(!old_entry.exists());
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(read_dir(tmp.path()).unwrap().count(), 2);
+
+        assert!(CacheEntry::validate(&entry, 3, Separator::Newline));
+        assert!(verify(&entry, 3));
+        assert!(!verify(&entry, 4));
+
+        // The truncated cache file is invalid and removed.
+        let contents = std::fs::read(&entry).unwrap();
+        std::fs::write(&entry, &contents[..contents.len() - 1]).unwrap();
+        assert!(!CacheEntry::validate(&entry, 3, Separator::Newline));
+        assert!(!entry.exists());
+        // Validating the removed cache file again is fine.
+        assert!(!CacheEntry::validate(&entry, 3, Separator::Newline));
+
+        // The legacy cache file is validated by counting the items.
+        let legacy = dir.join("1600000000_2");
+        std::fs::write(&legacy, "a\nb\n").unwrap();
+        assert!(CacheEntry::validate(&legacy, 2, Separator::Newline));
+        assert!(!CacheEntry::validate(&legacy, 3, Separator::Newline));
+        assert!(!legacy.exists());
+    }
+
+    #[test]
+    fn test_cache_file_info() {
+        let info = CacheFileInfo {
+            timestamp: 1600000000,
+            total: 100,
+            bytes: Some(4096),
+            fingerprint: Some(42),
+        };
+        assert_eq!(info.file_name(), "1600000000_100_4096B_42");
+        assert_eq!(CacheFileInfo::parse(&info.file_name()), Some(info));
+
+        let legacy = CacheFileInfo::parse("1600000000_100_42").unwrap();
+        assert_eq!((legacy.bytes, legacy.fingerprint), (None, Some(42)));
+        assert!(CacheFileInfo::parse("1600000000_100_42_0").is_none());
+        assert!(CacheFileInfo::parse("1600000000").is_none());
+    }
+
+    #[test]
+    fn test_tmp_path() {
+        let path = Path::new("/tmp/12345");
+        assert_ne!(tmp_path(path), tmp_path(path));
+    }
+
+    #[test]
+    fn test_parse_size_and_duration() {
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert_eq!(parse_size("512M").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_size("2g").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert!(parse_size("1T").is_err());
+
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(30 * 60));
+        assert_eq!(
+            parse_duration("7d").unwrap(),
+            Duration::from_secs(7 * 24 * 60 * 60)
+        );
+        assert!(parse_duration("7").is_err());
+    }
+}
@@ -1,6 +1,9 @@
+use crate::cmd::output_format;
 use crate::light_command::{set_current_dir, LightCommand};
 use anyhow::Result;
+use filter::Separator;
 use icon::IconPainter;
+use printer::OutputFormat;
 use std::path::PathBuf;
 use std::process::Command;
 use structopt::StructOpt;
@@ -23,6 +26,14 @@ pub struct Exec {
     /// Specify the threshold for writing the output of command to a tempfile.
     #[structopt(long = "output-threshold", default_value = "100000")]
     output_threshold: usize,
+
+    /// Read the output items of CMD delimited by NUL instead of newline, e.g., `git ls-files -z`.
+    #[structopt(long)]
+    read0: bool,
+
+    /// Print the items delimited by NUL, same to `--format null-separated`.
+    #[structopt(long)]
+    print0: bool,
 }
 
 impl Exec {
@@ -48,22 +59,34 @@ impl Exec {
         number: Option<usize>,
         icon_painter: Option<IconPainter>,
         no_cache: bool,
+        format: Option<OutputFormat>,
     ) -> Result<()> {
         let mut exec_cmd = self.prepare_exec_cmd();
 
+        let separator = if self.read0 {
+            Separator::Null
+        } else {
+            Separator::Newline
+        };
+
         let mut light_cmd = LightCommand::new(
             &mut exec_cmd,
             number,
             self.output.clone(),
             icon_painter,
             self.output_threshold,
-        );
+        )
+        .with_dir(self.cmd_dir.clone())
+        .with_format(output_format(self.print0, format)?)
+        .with_separator(separator);
+
+        // The exact command string is used as the cache key.
+        let mut args = vec![self.cmd.as_str()];
 
-        let args = self
-            .cmd
-            .split_whitespace()
-            .map(Into::into)
-            .collect::<Vec<_>>();
+        // The NUL-delimited output is cached separately.
+        if self.read0 {
+            args.push("--read0");
+        }
 
         if !no_cache && self.cmd_dir.is_some() {
             light_cmd.try_cache_or_execute(&args, self.cmd_dir.clone().unwrap())
@@ -1,12 +1,20 @@
-use anyhow::Result;
+use crate::cmd::output_format;
+use anyhow::{anyhow, Result};
 use filter::{
-    matcher::{Algo, Bonus, MatchType},
-    subprocess, Source,
+    matcher::{
+        Algo, Bonus, BonusContext, CaseMatching, MatchType, Matcher, Tiebreak, WeightedBonus,
+    },
+    read_byte_lines, subprocess, Separator, Source,
 };
 use icon::IconPainter;
-use source_item::SourceItem;
+use printer::{Output, OutputFormat};
+use source_item::{FieldRanges, FieldSpec, SourceItem};
+use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 use structopt::StructOpt;
+use utility::open_decompressed;
+
+type SourceItemIter = Box<dyn Iterator<Item = SourceItem>>;
 
 /// Execute the shell command
 #[derive(StructOpt, Debug, Clone)]
@@ -35,30 +43,137 @@ pub struct Filter {
     #[structopt(short, long, possible_values = &MatchType::variants(), case_insensitive = true)]
     match_type: Option<MatchType>,
 
-    /// Add a bonus to the score of base matching algorithm.
-    #[structopt(short, long, possible_values = &Bonus::variants(), case_insensitive = true)]
-    bonus: Option<Bonus>,
+    /// Add a bonus to the score of base matching algorithm, can be specified multiple times.
+    ///
+    /// In the form of `name[=arg][*weight]`, available bonuses: filename, pathdepth,
+    /// recentlymodified[=DIR], proximity=FILE, extension=EXT1,EXT2, none. The relative paths
+    /// are resolved against --cmd-dir.
+    #[structopt(short, long, number_of_values = 1)]
+    bonus: Vec<String>,
+
+    /// Case sensitivity of the filter algorithm, smart case by default.
+    #[structopt(long, possible_values = &CaseMatching::variants(), case_insensitive = true)]
+    case_matching: Option<CaseMatching>,
+
+    /// Comma-separated rules to rank the items with the same score, in the original order by default.
+    #[structopt(
+        long,
+        use_delimiter = true,
+        possible_values = &Tiebreak::variants(),
+        case_insensitive = true
+    )]
+    tiebreak: Vec<Tiebreak>,
+
+    /// Ignore the diacritics and full-width forms, e.g., `cafe` matches `café` and `ｃａｆｅ`.
+    #[structopt(long)]
+    normalize: bool,
 
     /// Synchronous filtering, returns after the input stream is complete.
     #[structopt(short, long)]
     sync: bool,
+
+    /// Literal field delimiter for --nth and --with-nth, AWK-style whitespace splitting by default.
+    #[structopt(long)]
+    delimiter: Option<String>,
+
+    /// Limit the matching scope to the given fields, e.g., 1, -1, 2.., ..3, 1..-2.
+    ///
+    /// Applied on the displayed text when --with-nth is also specified.
+    #[structopt(long)]
+    nth: Option<FieldRanges>,
+
+    /// Transform the presentation of each line to the given fields.
+    #[structopt(long)]
+    with_nth: Option<FieldRanges>,
+
+    /// Read the input items delimited by NUL instead of newline, e.g., the output of `fd -0`.
+    #[structopt(long)]
+    read0: bool,
+
+    /// Print the filtered items delimited by NUL, same to `--format null-separated`.
+    #[structopt(long)]
+    print0: bool,
 }
 
 impl Filter {
+    fn exec_cmd(&self, cmd_str: &str) -> subprocess::Exec {
+        if let Some(ref dir) = self.cmd_dir {
+            subprocess::Exec::shell(cmd_str).cwd(dir)
+        } else {
+            subprocess::Exec::shell(cmd_str)
+        }
+    }
+
+    fn field_spec(&self) -> FieldSpec {
+        FieldSpec::new(
+            self.delimiter.clone(),
+            self.nth.clone(),
+            self.with_nth.clone(),
+        )
+    }
+
+    fn separator(&self) -> Separator {
+        if self.read0 {
+            Separator::Null
+        } else {
+            Separator::Newline
+        }
+    }
+
     /// Firstly try building the Source from shell command, then the input file, finally reading the source from stdin.
-    fn generate_source<I: Iterator<Item = SourceItem>>(&self) -> Source<I> {
-        if let Some(ref cmd_str) = self.cmd {
-            if let Some(ref dir) = self.cmd_dir {
-                subprocess::Exec::shell(cmd_str).cwd(dir).into()
+    ///
+    /// The lines are converted to `SourceItem` upfront if --nth or --with-nth is specified.
+    fn generate_source(&self) -> Result<Source<SourceItemIter>> {
+        let field_spec = self.field_spec();
+        let separator = self.separator();
+
+        if field_spec.is_empty() {
+            let source = if let Some(ref cmd_str) = self.cmd {
+                Source::Exec(self.exec_cmd(cmd_str), separator)
+            } else if let Some(ref input) = self.input {
+                Source::File(input.clone(), separator)
             } else {
-                subprocess::Exec::shell(cmd_str).into()
-            }
-        } else {
-            self.input
-                .clone()
-                .map(Into::into)
-                .unwrap_or(Source::<I>::Stdin)
+                Source::Stdin(separator)
+            };
+            return Ok(source);
         }
+
+        let reader: Box<dyn BufRead> = if let Some(ref cmd_str) = self.cmd {
+            Box::new(BufReader::new(self.exec_cmd(cmd_str).stream_stdout()?))
+        } else if let Some(ref input) = self.input {
+            open_decompressed(input)?
+        } else {
+            Box::new(BufReader::new(std::io::stdin()))
+        };
+
+        Ok(Source::List(Box::new(
+            read_byte_lines(reader, separator)
+                .map(move |line| SourceItem::from_field_bytes(line, &field_spec)),
+        )))
+    }
+
+    fn matcher(&self) -> Result<Matcher> {
+        let cwd = match self.cmd_dir {
+            Some(ref dir) => dir.into(),
+            None => std::env::current_dir()?,
+        };
+        let ctx = BonusContext::new(cwd, None);
+        let bonuses = self
+            .bonus
+            .iter()
+            .map(|s| WeightedBonus::parse(s, &ctx))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!(e))?;
+
+        Ok(Matcher::new(
+            self.algo.clone().unwrap_or(Algo::Fzy),
+            self.match_type.clone().unwrap_or(MatchType::Full),
+            Bonus::None,
+            self.case_matching.clone().unwrap_or_default(),
+        )
+        .with_bonuses(bonuses)
+        .with_tiebreaks(self.tiebreak.clone())
+        .with_normalization(self.normalize))
     }
 
     /// Returns the results until the input stream is complete.
@@ -69,19 +184,24 @@ impl Filter {
         winwidth: Option<usize>,
         icon_painter: Option<IconPainter>,
     ) -> Result<()> {
-        let ranked = filter::sync_run::<std::iter::Empty<_>>(
-            &self.query,
-            self.generate_source(),
-            self.algo.clone().unwrap_or(Algo::Fzy),
-            self.match_type.clone().unwrap_or(MatchType::Full),
-            self.bonus.clone().unwrap_or_default(),
-        )?;
+        let ranked = filter::sync_run(&self.query, self.generate_source()?, self.matcher()?)?;
 
         printer::print_sync_filter_results(ranked, number, winwidth, icon_painter);
 
         Ok(())
     }
 
+    /// Prints the results in the given output format.
+    ///
+    /// The whole input is always filtered synchronously as there is no progress to report.
+    fn print_output(&self, number: Option<usize>, format: OutputFormat) -> Result<()> {
+        let ranked = filter::sync_run(&self.query, self.generate_source()?, self.matcher()?)?;
+
+        Output::from_filter_results(ranked, number).print(format)?;
+
+        Ok(())
+    }
+
     #[inline]
     fn dyn_run(
         &self,
@@ -89,15 +209,13 @@ impl Filter {
         winwidth: Option<usize>,
         icon_painter: Option<IconPainter>,
     ) -> Result<()> {
-        filter::dyn_run::<std::iter::Empty<_>>(
+        filter::dyn_run(
             &self.query,
-            self.generate_source(),
-            self.algo.clone(),
+            self.generate_source()?,
+            self.matcher()?,
             number,
             winwidth,
             icon_painter,
-            self.match_type.clone().unwrap_or(MatchType::Full),
-            self.bonus.clone().unwrap_or_default(),
         )
     }
 
@@ -106,8 +224,11 @@ impl Filter {
         number: Option<usize>,
         winwidth: Option<usize>,
         icon_painter: Option<IconPainter>,
+        format: Option<OutputFormat>,
     ) -> Result<()> {
-        if self.sync {
+        if let Some(format) = output_format(self.print0, format)? {
+            self.print_output(number, format)?;
+        } else if self.sync {
             self.sync_run(number, winwidth, icon_painter)?;
         } else {
             self.dyn_run(number, winwidth, icon_painter)?;
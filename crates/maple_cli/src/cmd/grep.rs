@@ -1,12 +1,13 @@
-use crate::cmd::cache::{cache_exists, send_response_from_cache, SendResponse};
+use crate::cmd::cache::{cache_exists, revalidate_cache, send_response_from_cache, SendResponse};
 use crate::light_command::{set_current_dir, LightCommand};
 use anyhow::{Context, Result};
 use filter::{
-    matcher::{Bonus, MatchType},
+    matcher::{Algo, Bonus, CaseMatching, MatchType, Matcher},
     subprocess::Exec,
     Source,
 };
 use icon::IconPainter;
+use printer::{Output, OutputFormat};
 use std::path::PathBuf;
 use std::process::Command;
 use structopt::StructOpt;
@@ -92,11 +93,12 @@ impl Grep {
         winwidth: Option<usize>,
         icon_painter: Option<IconPainter>,
         no_cache: bool,
+        format: Option<OutputFormat>,
     ) -> Result<()> {
         if self.sync {
-            self.sync_run(number, icon_painter)?;
+            self.sync_run(number, icon_painter, format)?;
         } else {
-            self.dyn_run(number, winwidth, icon_painter, no_cache)?;
+            self.dyn_run(number, winwidth, icon_painter, no_cache, format)?;
         }
         Ok(())
     }
@@ -104,7 +106,12 @@ impl Grep {
     /// Runs grep command and returns until its output stream is completed.
     ///
     /// Write the output to the cache file if neccessary.
-    fn sync_run(&self, number: Option<usize>, icon_painter: Option<IconPainter>) -> Result<()> {
+    fn sync_run(
+        &self,
+        number: Option<usize>,
+        icon_painter: Option<IconPainter>,
+        format: Option<OutputFormat>,
+    ) -> Result<()> {
         let grep_cmd = self
             .grep_cmd
             .clone()
@@ -127,7 +134,8 @@ impl Grep {
 
         cmd.args(&args[1..]);
 
-        let mut light_cmd = LightCommand::new_grep(&mut cmd, None, number, icon_painter, None);
+        let mut light_cmd =
+            LightCommand::new_grep(&mut cmd, None, number, icon_painter, None).with_format(format);
 
         light_cmd.execute(&args)?;
 
@@ -143,26 +151,36 @@ impl Grep {
         winwidth: Option<usize>,
         icon_painter: Option<IconPainter>,
         no_cache: bool,
+        format: Option<OutputFormat>,
     ) -> Result<()> {
         let do_dyn_filter = |source: Source<std::iter::Empty<_>>| {
+            let matcher = Matcher::new(
+                Algo::Fzy,
+                MatchType::IgnoreFilePath,
+                Bonus::None,
+                CaseMatching::Smart,
+            );
+            if let Some(format) = format {
+                let ranked = filter::sync_run(&self.grep_query, source, matcher)?;
+                Output::from_filter_results(ranked, number).print(format)?;
+                return Ok(());
+            }
             filter::dyn_run(
                 &self.grep_query,
                 source,
-                None,
+                matcher,
                 number,
                 winwidth,
                 icon_painter,
-                MatchType::IgnoreFilePath,
-                Bonus::None,
             )
         };
 
         let source: Source<std::iter::Empty<_>> = if let Some(ref tempfile) = self.input {
-            Source::File(tempfile.clone())
+            tempfile.clone().into()
         } else if let Some(ref dir) = self.cmd_dir {
             if !no_cache {
                 if let Ok((cached_file, _)) = cache_exists(&RG_ARGS, dir) {
-                    return do_dyn_filter(Source::File(cached_file));
+                    return do_dyn_filter(cached_file.into());
                 }
             }
             Exec::shell(RG_EXEC_CMD).cwd(dir).into()
@@ -213,6 +231,8 @@ impl RipGrepForerunner {
                     send_response_from_cache(
                         &cache,
                         total,
+                        revalidate_cache(&cache, dir),
+                        None,
                         SendResponse::Json,
                         Some(IconPainter::Grep),
                     );
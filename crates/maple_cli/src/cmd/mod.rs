@@ -5,3 +5,26 @@ pub mod filter;
 pub mod grep;
 pub mod helptags;
 pub mod tags;
+
+use anyhow::{anyhow, Result};
+use printer::OutputFormat;
+
+/// Returns the output format given `--print0` and `--format`.
+///
+/// `--print0` is a shorthand for `--format null-separated`, which replaces the newline of
+/// `--format plain`.
+pub(crate) fn output_format(
+    print0: bool,
+    format: Option<OutputFormat>,
+) -> Result<Option<OutputFormat>> {
+    match (print0, format) {
+        (false, format) => Ok(format),
+        (true, None)
+        | (true, Some(OutputFormat::Plain))
+        | (true, Some(OutputFormat::NullSeparated)) => Ok(Some(OutputFormat::NullSeparated)),
+        (true, Some(format)) => Err(anyhow!(
+            "--print0 can not be used with --format {:?}",
+            format
+        )),
+    }
+}
@@ -1,14 +1,19 @@
-use crate::cmd::cache::{cache_exists, send_response_from_cache, CacheEntry, SendResponse};
+use crate::cmd::cache::{
+    cache_exists, revalidate_cache, send_response_from_cache, CacheEntry, SendResponse,
+};
+use crate::light_command::{command_warning, report_command_error};
 use anyhow::{anyhow, Result};
 use filter::{
-    matcher::{Bonus, MatchType},
-    subprocess, Source,
+    matcher::{Algo, Bonus, CaseMatching, MatchType, Matcher},
+    Source,
 };
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use source_item::{ItemMetadata, SourceItem};
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 use structopt::StructOpt;
+use utility::{as_std_command, command_timeout, CancelToken, CommandError, SupervisedChild};
 
 const BASE_TAGS_CMD: &str = "ctags -R -x --output-format=json --fields=+n";
 
@@ -52,6 +57,16 @@ impl TagInfo {
             pattern = &self.pattern[2..pat_len - 2].trim(),
         )
     }
+
+    /// Converts into the formatted line with the typed metadata.
+    pub fn into_source_item(self) -> SourceItem {
+        SourceItem::from(self.format()).with_metadata(
+            ItemMetadata::default()
+                .with_path(self.path)
+                .with_lnum(self.line)
+                .with_kind(self.kind),
+        )
+    }
 }
 
 /// Generate ctags recursively given the directory.
@@ -84,31 +99,42 @@ pub struct Tags {
     exclude: Vec<String>,
 }
 
-fn formatted_tags_stream(args: &[&str], dir: &PathBuf) -> Result<impl Iterator<Item = String>> {
-    let stdout_stream = subprocess::Exec::shell(args.join(" "))
-        .cwd(dir)
-        .stream_stdout()?;
-    Ok(BufReader::new(stdout_stream).lines().filter_map(|line| {
-        line.ok().and_then(|tag| {
-            if let Ok(tag) = serde_json::from_str::<TagInfo>(&tag) {
-                Some(tag.format())
-            } else {
-                None
-            }
-        })
-    }))
+/// Spawns ctags in `dir`, the returned child should be waited after the tags are read.
+fn tags_stream(
+    args: &[&str],
+    dir: &PathBuf,
+) -> Result<(SupervisedChild, impl Iterator<Item = TagInfo>)> {
+    let mut cmd = as_std_command(args.join(" "), Some(dir));
+    let mut child = SupervisedChild::spawn(&mut cmd, command_timeout(), CancelToken::new())?;
+    let stdout_stream = child
+        .take_stdout()
+        .ok_or_else(|| anyhow!("Couldn't capture the stdout of ctags"))?;
+    let tags = BufReader::new(stdout_stream).lines().filter_map(|line| {
+        line.ok()
+            .and_then(|tag| serde_json::from_str::<TagInfo>(&tag).ok())
+    });
+    Ok((child, tags))
 }
 
-fn create_tags_cache(args: &[&str], dir: &PathBuf) -> Result<(PathBuf, usize)> {
-    let tags_stream = formatted_tags_stream(args, dir)?;
+/// Creates the cache of tags, along with the warning of ctags if it fails but the tags are
+/// still generated.
+fn create_tags_cache(
+    args: &[&str],
+    dir: &PathBuf,
+) -> Result<(PathBuf, usize, Option<CommandError>)> {
+    let (child, tags_stream) = tags_stream(args, dir)?;
     let mut total = 0usize;
-    let mut formatted_tags_stream = tags_stream.map(|x| {
+    let mut formatted_tags_stream = tags_stream.map(|tag| {
         total += 1;
-        x
+        tag.format()
     });
     let lines = formatted_tags_stream.join("\n");
+    let warning = match child.wait() {
+        Ok(_) => None,
+        Err(e) => Some(command_warning(e)?),
+    };
     let cache = CacheEntry::create(args, Some(dir.clone()), total, lines)?;
-    Ok((cache, total))
+    Ok((cache, total, warning))
 }
 
 impl Tags {
@@ -140,26 +166,53 @@ impl Tags {
         let cmd_args = cmd_args.iter().map(|x| x.as_str()).collect::<Vec<_>>();
 
         if self.forerunner {
-            let (cache, total) = if no_cache {
-                create_tags_cache(&cmd_args, &self.dir)?
-            } else if let Ok(cached_info) = cache_exists(&cmd_args, &self.dir) {
-                cached_info
+            let create_cache = || {
+                create_tags_cache(&cmd_args, &self.dir)
+                    .map(|(cache, total, warning)| (cache, total, warning, false))
+            };
+            let cache = if no_cache {
+                create_cache()
+            } else if let Ok((cache, total)) = cache_exists(&cmd_args, &self.dir) {
+                let stale = revalidate_cache(&cache, &self.dir);
+                Ok((cache, total, None, stale))
             } else {
-                create_tags_cache(&cmd_args, &self.dir)?
+                create_cache()
             };
-            send_response_from_cache(&cache, total, SendResponse::Json, icon_painter);
+            match cache {
+                Ok((cache, total, warning, stale)) => send_response_from_cache(
+                    &cache,
+                    total,
+                    stale,
+                    warning.as_ref(),
+                    SendResponse::Json,
+                    icon_painter,
+                ),
+                Err(e) => report_command_error(e)?,
+            }
             return Ok(());
         } else {
+            let (child, tags_stream) = tags_stream(&cmd_args, &self.dir)?;
             filter::dyn_run(
                 &self.query,
-                Source::List(formatted_tags_stream(&cmd_args, &self.dir)?.map(Into::into)),
-                None,
+                Source::List(tags_stream.map(TagInfo::into_source_item)),
+                Matcher::new(
+                    Algo::Fzy,
+                    MatchType::TagName,
+                    Bonus::None,
+                    CaseMatching::Smart,
+                ),
                 Some(30),
                 None,
                 icon_painter,
-                MatchType::TagName,
-                Bonus::None,
             )?;
+            // The results filtered so far have been printed, the tags generated by the failed
+            // ctags are still usable.
+            if let Err(e) = child.wait() {
+                match command_warning(e) {
+                    Ok(warning) => println_json!(warning),
+                    Err(e) => report_command_error(e)?,
+                }
+            }
         }
 
         Ok(())
@@ -1,23 +1,136 @@
-use crate::cmd::cache::CacheEntry;
+use crate::cmd::cache::{revalidate_cache, tmp_path, AtomicFile, CacheEntry};
 use anyhow::{anyhow, Result};
+use filter::{read_byte_lines, Separator};
 use icon::IconPainter;
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::{Command, Output};
-use utility::{get_cached_entry, read_first_lines, remove_dir_contents};
-
-/// Remove the last element if it's empty string.
-#[inline]
-fn trim_trailing(lines: &mut Vec<String>) {
-    if let Some(last_line) = lines.last() {
-        // " " len is 4.
-        if last_line.is_empty() || last_line.len() == 4 {
-            lines.remove(lines.len() - 1);
+use printer::{Output, OutputFormat, OutputItem};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use utility::{
+    command_timeout, dir_fingerprint, get_cached_entry, open_decompressed, CancelToken,
+    CommandError, SupervisedChild,
+};
+
+/// Number of the items displayed directly if the whole output is written to the cache file.
+const CACHED_ITEMS_TO_DISPLAY: usize = 100;
+
+/// Reads the first `number` items of the cache file.
+fn read_first_items(
+    path: &Path,
+    number: usize,
+    separator: Separator,
+) -> std::io::Result<impl Iterator<Item = Vec<u8>>> {
+    Ok(read_byte_lines(open_decompressed(path)?, separator).take(number))
+}
+
+/// Reads all the items of the complete cache file, including the trailing separator.
+fn read_all_items(path: &Path, separator: Separator) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut reader = open_decompressed(path)?;
+    let mut items = Vec::new();
+    loop {
+        let mut item = Vec::new();
+        if reader.read_until(separator.byte(), &mut item)? == 0 {
+            break;
+        }
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// Prints an item in the per-item `format` immediately.
+fn print_item(item: &[u8], format: OutputFormat) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    OutputItem::from(item.to_vec()).write_to(&mut stdout, format)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Reports the error of command to Vim as `{"error": {..}}`, the other errors are returned.
+pub fn report_command_error(e: anyhow::Error) -> Result<()> {
+    match e.downcast::<CommandError>() {
+        Ok(error) => {
+            println_json!(error);
+            Ok(())
         }
+        Err(e) => Err(e),
     }
 }
 
+/// Returns the error of command as a warning if the output of command is still usable, see
+/// [`CommandError::keeps_output`], the other errors are returned as is.
+pub fn command_warning(e: anyhow::Error) -> Result<CommandError> {
+    match e.downcast::<CommandError>() {
+        Ok(error) if error.keeps_output() => Ok(error),
+        Ok(error) => Err(error.into()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Output of the command read by streaming, see [`read_streaming`].
+struct StreamedOutput<W> {
+    /// Number of all the items.
+    total: usize,
+    /// The leading items held in memory, including the trailing separator.
+    items: Vec<Vec<u8>>,
+    /// Writer of the whole output, created on the first item if the output is cached.
+    cache: Option<W>,
+}
+
+/// Reads the items of `reader` one by one without holding the whole output in memory.
+///
+/// Each of the first `number` items, or every item if `number` is None, is passed to `on_item`
+/// as soon as it arrives. If `cache` is true, the whole output is teed into the writer created
+/// by `create_cache` from the first item and only the first [`CACHED_ITEMS_TO_DISPLAY`] items
+/// are held in memory, otherwise the first `number` items are held.
+fn read_streaming<R: BufRead, W: Write>(
+    mut reader: R,
+    separator: Separator,
+    number: Option<usize>,
+    cache: bool,
+    mut create_cache: impl FnMut() -> Result<W>,
+    mut on_item: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<StreamedOutput<W>> {
+    let mut output = StreamedOutput::<W> {
+        total: 0,
+        items: Vec::new(),
+        cache: None,
+    };
+    let number = number.unwrap_or(usize::MAX);
+    let items_to_hold = if cache {
+        CACHED_ITEMS_TO_DISPLAY.min(number)
+    } else {
+        number
+    };
+    let mut item = Vec::new();
+    loop {
+        item.clear();
+        if reader.read_until(separator.byte(), &mut item)? == 0 {
+            break;
+        }
+        output.total += 1;
+
+        if output.total <= number {
+            on_item(separator.trim_separator(&item))?;
+        }
+
+        if cache {
+            if output.cache.is_none() {
+                output.cache = Some(create_cache()?);
+            }
+            if let Some(ref mut cache) = output.cache {
+                cache.write_all(&item)?;
+            }
+        }
+
+        if output.items.len() < items_to_hold {
+            output.items.push(item.clone());
+        }
+    }
+    Ok(output)
+}
+
 pub fn set_current_dir(cmd: &mut Command, cmd_dir: Option<PathBuf>) {
     if let Some(cmd_dir) = cmd_dir {
         // If cmd_dir is not a directory, use its parent as current dir.
@@ -40,6 +153,10 @@ pub struct CommandEnv {
     pub output: Option<String>,
     pub icon_painter: Option<IconPainter>,
     pub output_threshold: usize,
+    pub format: Option<OutputFormat>,
+    pub separator: Separator,
+    /// Timeout of the command, defaults to the env var `CLAP_COMMAND_TIMEOUT`.
+    pub timeout: Option<Duration>,
 }
 
 impl Default for CommandEnv {
@@ -51,6 +168,9 @@ impl Default for CommandEnv {
             output: None,
             icon_painter: None,
             output_threshold: 100_000usize,
+            format: None,
+            separator: Separator::Newline,
+            timeout: command_timeout(),
         }
     }
 }
@@ -74,49 +194,38 @@ impl CommandEnv {
     }
 
     #[inline]
-    pub fn try_paint_icon<'b>(
-        &self,
-        top_n: impl std::iter::Iterator<Item = &'b str>,
-    ) -> Vec<String> {
+    pub fn try_paint_icon(&self, top_n: impl Iterator<Item = impl AsRef<str>>) -> Vec<String> {
         if let Some(ref painter) = self.icon_painter {
-            top_n.map(|x| painter.paint(x)).collect()
+            top_n.map(|x| painter.paint(x.as_ref())).collect()
         } else {
-            top_n.map(Into::into).collect()
+            top_n.map(|x| x.as_ref().into()).collect()
         }
     }
 
-    // TODO: add a cache upper bound?
-    #[inline]
-    pub fn should_do_cache(&self) -> bool {
-        self.total > self.output_threshold
-    }
-
-    #[inline]
-    pub fn new_cache_entry(&self, args: &[&str]) -> Result<PathBuf> {
-        if let Some(ref output) = self.output {
-            Ok(output.into())
-        } else {
-            CacheEntry::new(args, self.dir.clone(), self.total)
+    /// Starts writing the whole output of command to the cache file, or to `output` if
+    /// specified.
+    fn create_cache(&self, args: &[&str]) -> Result<AtomicFile> {
+        match self.output {
+            Some(ref output) => AtomicFile::create(tmp_path(Path::new(output)), false),
+            None => CacheEntry::create_writer(&CacheEntry::dir(args, self.dir.as_deref())),
         }
     }
 
-    /// Writes the whole stdout of LightCommand to a tempfile.
-    pub fn do_cache(&self, cmd_stdout: &[u8], args: &[&str]) -> Result<PathBuf> {
-        let tempfile = self.new_cache_entry(args)?;
-
-        // Remove the other outdated cache file if there are any.
-        //
-        // There should be only one cache file in parent_dir at this moment.
-        if let Some(parent_dir) = tempfile.parent() {
-            remove_dir_contents(&parent_dir.to_path_buf())?;
+    /// Completes the cache file written by streaming, returns its path.
+    fn persist_cache(&self, cache: AtomicFile, args: &[&str]) -> Result<PathBuf> {
+        match self.output {
+            Some(ref output) => {
+                cache.persist(Path::new(output))?;
+                Ok(output.into())
+            }
+            None => {
+                let entry_dir = CacheEntry::dir(args, self.dir.as_deref());
+                let fingerprint = self.dir.as_deref().and_then(dir_fingerprint);
+                let entry = CacheEntry::persist(cache, &entry_dir, self.total, fingerprint)?;
+                CacheEntry::record(&entry, args, self.dir.as_deref(), self.total);
+                Ok(entry)
+            }
         }
-
-        File::create(&tempfile)?.write_all(cmd_stdout)?;
-
-        // FIXME find the nth newline index of stdout.
-        // let _end = std::cmp::min(cmd_stdout.len(), 500);
-
-        Ok(tempfile)
     }
 }
 
@@ -157,76 +266,109 @@ impl<'a> LightCommand<'a> {
         }
     }
 
-    /// Collect the output of command, exit directly if any error happened.
-    fn output(&mut self) -> Result<Output> {
-        let cmd_output = self.cmd.output()?;
-
-        // vim-clap does not handle the stderr stream, we just pass the error info via stdout.
-        if !cmd_output.status.success() && !cmd_output.stderr.is_empty() {
-            let error = format!("{}", String::from_utf8_lossy(&cmd_output.stderr));
-            println_json!(error);
-            std::process::exit(1);
-        }
-
-        Ok(cmd_output)
+    /// Prints the results in the versioned output `format` instead of the JSON for Vim.
+    pub fn with_format(mut self, format: Option<OutputFormat>) -> Self {
+        self.env.format = format;
+        self
     }
 
-    /// Normally we only care about the top N items and number of total results if it's not a
-    /// forerunner job.
-    fn minimalize_job_overhead(&self, stdout: &[u8]) -> Result<()> {
-        if let Some(number) = self.env.number {
-            // TODO: do not have to into String for whole stdout, find the nth index of newline.
-            // &cmd_output.stdout[..nth_newline_index]
-            let stdout_str = String::from_utf8_lossy(&stdout);
-            let lines = self.try_prepend_icon(stdout_str.split('\n').take(number));
-            let total = self.env.total;
-            println_json!(total, lines);
-            return Ok(());
-        }
-        Err(anyhow!(
-            "--number is unspecified, no overhead minimalization"
-        ))
+    /// Sets the working directory used as the cache key, even if the cache is not looked up.
+    pub fn with_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.env.dir = dir;
+        self
     }
 
-    fn try_prepend_icon<'b>(&self, top_n: impl std::iter::Iterator<Item = &'b str>) -> Vec<String> {
-        let mut lines = self.env.try_paint_icon(top_n);
-        trim_trailing(&mut lines);
-        lines
+    /// Splits the output of command by `separator` instead of newline.
+    pub fn with_separator(mut self, separator: Separator) -> Self {
+        self.env.separator = separator;
+        self
     }
 
-    /// Cache the stdout into a tempfile if the output threshold exceeds.
-    fn try_cache(&self, cmd_stdout: &[u8], args: &[&str]) -> Result<(String, Option<PathBuf>)> {
-        if self.env.should_do_cache() {
-            let cache_file = self.env.do_cache(cmd_stdout, args)?;
-            Ok((
-                // lines used for displaying directly.
-                // &cmd_output.stdout[..nth_newline_index]
-                String::from_utf8_lossy(cmd_stdout).into(),
-                Some(cache_file),
-            ))
-        } else {
-            Ok((String::from_utf8_lossy(cmd_stdout).into(), None))
+    /// Prints the items held in memory, the per-item formats have been printed by streaming.
+    ///
+    /// `warning` is the error of command whose output is still usable, which is printed to
+    /// stderr for the per-item formats.
+    fn print_output(
+        &self,
+        items: Vec<Vec<u8>>,
+        tempfile: Option<PathBuf>,
+        warning: Option<CommandError>,
+    ) -> Result<()> {
+        let total = self.env.total;
+        let separator = self.env.separator;
+        let items = items.iter().map(|item| separator.trim_separator(item));
+        match self.env.format {
+            Some(format) if format.is_per_item() => {
+                if let Some(warning) = warning {
+                    eprintln!("warning: {}", warning);
+                }
+            }
+            Some(format) => {
+                let mut output = Output::new(total, items.map(|item| item.to_vec()));
+                output.tempfile = tempfile;
+                output.warning = warning;
+                output.print(format)?;
+            }
+            None => {
+                let lines = self
+                    .env
+                    .try_paint_icon(items.map(|item| String::from_utf8_lossy(item)));
+                if let Some(tempfile) = tempfile {
+                    println_json!(total, lines, tempfile, warning);
+                } else {
+                    println_json!(total, lines, warning);
+                }
+            }
         }
+        Ok(())
     }
 
     /// Firstly try the cache given the command args and working dir.
-    /// If the cache exists, returns the cache file directly.
+    /// If the cache exists, returns the cache file directly, which is refreshed in the
+    /// background if it's stale.
     pub fn try_cache_or_execute(&mut self, args: &[&str], cmd_dir: PathBuf) -> Result<()> {
         if let Ok(cached_entry) = get_cached_entry(args, &cmd_dir) {
-            if let Ok(total) = CacheEntry::get_total(&cached_entry) {
+            let tempfile = cached_entry.path();
+            if let Some(total) = CacheEntry::get_total(&cached_entry)
+                .ok()
+                .filter(|&total| CacheEntry::validate(&tempfile, total, self.env.separator))
+            {
                 let using_cache = true;
-                let tempfile = cached_entry.path();
-                if let Ok(lines_iter) = read_first_lines(&tempfile, 100) {
+                CacheEntry::touch(&tempfile);
+                let stale = revalidate_cache(&tempfile, &cmd_dir);
+                if let Some(format) = self.env.format {
+                    let number = match self.env.number {
+                        Some(number) => number,
+                        None if format.is_per_item() => total,
+                        None => CACHED_ITEMS_TO_DISPLAY,
+                    };
+                    let items = read_first_items(&tempfile, number, self.env.separator)?;
+                    if format.is_per_item() {
+                        for item in items {
+                            print_item(&item, format)?;
+                        }
+                        return Ok(());
+                    }
+                    let mut output = Output::new(total, items);
+                    output.tempfile = Some(tempfile);
+                    output.using_cache = using_cache;
+                    output.stale = stale;
+                    output.print(format)?;
+                    return Ok(());
+                }
+                if let Ok(lines_iter) =
+                    read_first_items(&tempfile, CACHED_ITEMS_TO_DISPLAY, self.env.separator)
+                {
+                    let lines_iter = lines_iter.map(|x| String::from_utf8_lossy(&x).into_owned());
                     let lines: Vec<String> = if let Some(ref painter) = self.env.icon_painter {
                         lines_iter.map(|x| painter.paint(&x)).collect()
                     } else {
                         lines_iter.collect()
                     };
-                    println_json!(using_cache, total, tempfile, lines);
+                    println_json!(using_cache, stale, total, tempfile, lines);
                 } else {
-                    println_json!(using_cache, total, tempfile);
+                    println_json!(using_cache, stale, total, tempfile);
                 }
-                // TODO: refresh the cache or mark it as outdated?
                 return Ok(());
             }
         }
@@ -236,32 +378,153 @@ impl<'a> LightCommand<'a> {
         self.execute(args)
     }
 
-    /// Execute the command directly and capture the output.
+    /// Execute the command directly and read its output by streaming.
     ///
-    /// Truncate the results to `self.number` if specified,
-    /// otherwise print the total results or write them to
-    /// a tempfile if they are more than `self.output_threshold`.
-    /// This cached tempfile can be reused on the following runs.
+    /// Truncate the results to `self.number` if specified, otherwise print the total results or
+    /// write them to a tempfile if they are more than `self.output_threshold`, in which case only
+    /// the first results are printed. This cached tempfile can be reused on the following runs.
+    ///
+    /// The whole output is never held in memory, it's teed into the temp file of cache as it
+    /// arrives and only the first items are held. The items in the per-item formats are printed
+    /// as soon as they arrive.
+    ///
+    /// The command is killed once it exceeds `self.timeout`, the failure of command is reported
+    /// as `{"error": {..}}` for Vim or returned in the output formats. If the command fails by
+    /// itself, e.g., `rg` exits with 2 on an unreadable file, its output is still printed and
+    /// cached along with the error as `warning`.
     pub fn execute(&mut self, args: &[&str]) -> Result<()> {
-        let cmd_output = self.output()?;
-        let cmd_stdout = &cmd_output.stdout;
+        let mut child = SupervisedChild::spawn(self.cmd, self.env.timeout, CancelToken::new())?;
+        let stdout = child
+            .take_stdout()
+            .ok_or_else(|| anyhow!("Couldn't capture the stdout of command"))?;
 
-        self.env.total = bytecount::count(cmd_stdout, b'\n');
+        let per_item_format = self.env.format.filter(|format| format.is_per_item());
+        let env = &self.env;
+        let streamed = read_streaming(
+            BufReader::new(stdout),
+            env.separator,
+            env.number,
+            env.number.is_none(),
+            || env.create_cache(args),
+            |item| match per_item_format {
+                Some(format) => print_item(item, format),
+                None => Ok(()),
+            },
+        )?;
 
-        if self.minimalize_job_overhead(cmd_stdout).is_ok() {
-            return Ok(());
-        }
+        // The partially written cache file is removed once `streamed` is dropped.
+        let warning = match child.wait() {
+            Ok(_) => None,
+            Err(e) => match command_warning(e) {
+                Ok(warning) => Some(warning),
+                // vim-clap does not handle the stderr stream, we just pass the error info via
+                // stdout.
+                Err(e) => {
+                    return match self.env.format {
+                        Some(_) => Err(e),
+                        None => report_command_error(e),
+                    }
+                }
+            },
+        };
 
-        // Write the output to a tempfile if the lines are too many.
-        let (stdout_str, tempfile) = self.try_cache(&cmd_stdout, args)?;
-        let lines = self.try_prepend_icon(stdout_str.split('\n'));
-        let total = self.env.total;
-        if let Some(tempfile) = tempfile {
-            println_json!(total, lines, tempfile);
-        } else {
-            println_json!(total, lines);
-        }
+        self.env.total = streamed.total;
+        let (items, tempfile) = match streamed.cache {
+            Some(cache) if streamed.total > self.env.output_threshold => {
+                (streamed.items, Some(self.env.persist_cache(cache, args)?))
+            }
+            // The whole output within the threshold is sent back instead, read from the temp
+            // file which is then discarded.
+            Some(mut cache)
+                if per_item_format.is_none() && streamed.total > streamed.items.len() =>
+            {
+                cache.finish()?;
+                (read_all_items(cache.tmp(), self.env.separator)?, None)
+            }
+            _ => (streamed.items, None),
+        };
 
-        Ok(())
+        self.print_output(items, tempfile, warning)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_streaming() {
+        let input = b"a\nb\r\nc\nd\ne".to_vec();
+
+        let mut arrived = Vec::new();
+        let streamed = read_streaming(
+            &input[..],
+            Separator::Newline,
+            Some(2),
+            false,
+            || -> Result<Vec<u8>> { unreachable!("--number is never cached") },
+            |item| {
+                arrived.push(item.to_vec());
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(streamed.total, 5);
+        assert_eq!(arrived, vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(streamed.items, vec![b"a\n".to_vec(), b"b\r\n".to_vec()]);
+
+        // The whole output is teed into the cache from the first item, only the leading items
+        // are held.
+        let input = (0..200)
+            .map(|i| format!("{}\n", i))
+            .collect::<String>()
+            .into_bytes();
+        let streamed = read_streaming(
+            &input[..],
+            Separator::Newline,
+            None,
+            true,
+            || Ok(Vec::new()),
+            |_| Ok(()),
+        )
+        .unwrap();
+        assert_eq!(streamed.total, 200);
+        assert_eq!(streamed.items.len(), CACHED_ITEMS_TO_DISPLAY);
+        assert_eq!(streamed.cache.unwrap(), input);
+
+        // The unterminated last item is counted.
+        let streamed = read_streaming(
+            &b"a\nb"[..],
+            Separator::Newline,
+            None,
+            true,
+            || Ok(Vec::new()),
+            |_| Ok(()),
+        )
+        .unwrap();
+        assert_eq!(streamed.total, 2);
+        assert_eq!(streamed.items, vec![b"a\n".to_vec(), b"b".to_vec()]);
+        assert_eq!(streamed.cache.unwrap(), b"a\nb");
+
+        // The output within the threshold is read back from the cache file.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        std::fs::write(&path, b"a\nb").unwrap();
+        assert_eq!(
+            read_all_items(&path, Separator::Newline).unwrap(),
+            vec![b"a\n".to_vec(), b"b".to_vec()]
+        );
+
+        let streamed = read_streaming(
+            &b""[..],
+            Separator::Newline,
+            None,
+            true,
+            || -> Result<Vec<u8>> { unreachable!("the output is empty") },
+            |_| Ok(()),
+        )
+        .unwrap();
+        assert_eq!(streamed.total, 0);
+        assert!(streamed.cache.is_none());
     }
 }
@@ -8,38 +8,49 @@
 //! # Fork differences
 //!
 //! * Support "smart case" searching. Ref https://github.com/liuchengxu/vim-clap/pull/541
+//! * Support case sensitive and case insensitive searching explicitly.
 
 mod scoring_utils;
 
 use crate::scoring_utils::*;
 
+pub use crate::scoring_utils::{
+    Score, SCORE_GAP_INNER, SCORE_GAP_LEADING, SCORE_GAP_TRAILING, SCORE_MATCH_CONSECUTIVE,
+};
+
 pub type MatchWithPositions = (Score, Vec<usize>);
 
+/// Matches in the "smart case" way, i.e., case sensitive only if the needle contains any
+/// uppercase char.
 pub fn match_and_score_with_positions(needle: &str, haystack: &str) -> Option<MatchWithPositions> {
-    let lowercased;
-    let haystack = if needle.chars().any(|c| c.is_uppercase()) {
-        haystack
-    } else {
-        lowercased = haystack.to_lowercase();
-        &lowercased
+    let case_sensitive = needle.chars().any(|c| c.is_uppercase());
+    match_and_score_with_case(needle, haystack, case_sensitive)
+}
+
+/// Matches case-sensitively or case-insensitively depending on `case_sensitive`.
+pub fn match_and_score_with_case(
+    needle: &str,
+    haystack: &str,
+    case_sensitive: bool,
+) -> Option<MatchWithPositions> {
+    if case_sensitive {
+        return matches(needle, haystack)
+            .map(|needle_length| score_with_positions(needle, needle_length, haystack));
+    }
+
+    // Lowercase char by char to keep the positions valid for the origin haystack, as the
+    // lowercase form of a char can consist of multiple chars.
+    let lowercase = |s: &str| {
+        s.chars()
+            .map(|c| c.to_lowercase().next().unwrap_or(c))
+            .collect::<String>()
     };
 
-    // The another approach to avoid the unnecessary allocation in the case of `needle` contains
-    // any uppercase char is using `Option`.
-    //
-    // Ref https://github.com/liuchengxu/vim-clap/pull/541#discussion_r507020114
-
-    /*
-      let lowercase_haystack = if needle.chars().any(|c| c.is_uppercase()) {
-          None
-      } else {
-          Some(haystack.to_lowercase())
-      };
-      let haystack = lowercase_haystack.as_deref().unwrap_or(haystack);
-    */
-
-    matches(needle, haystack)
-        .map(|needle_length| score_with_positions(needle, needle_length, haystack))
+    let needle = lowercase(needle);
+    let haystack = lowercase(haystack);
+
+    matches(&needle, &haystack)
+        .map(|needle_length| score_with_positions(&needle, needle_length, &haystack))
 }
 
 /// Searches for needle's chars in the haystack.
@@ -212,7 +223,8 @@ fn compute_bonus(haystack: &str, haystack_length: usize) -> Vec<Score> {
         })
 }
 
-fn bonus_for_char(prev: char, current: char) -> Score {
+/// Returns the bonus of matching `current` given the char before it.
+pub fn bonus_for_char(prev: char, current: char) -> Score {
     match current {
         'a'..='z' | '0'..='9' => bonus_for_prev(prev),
         'A'..='Z' => match prev {
@@ -278,6 +290,15 @@ mod tests {
         assert_eq!(result, None);
     }
 
+    #[test]
+    fn explicit_case() {
+        let result = match_and_score_with_case("Def", "abc def ghi", false);
+        assert_eq!(result, Some((552, vec![4, 5, 6])));
+
+        let result = match_and_score_with_case("def", "abc DEF ghi", true);
+        assert_eq!(result, None);
+    }
+
     #[test]
     fn smart_case() {
         let result = match_and_score_with_positions("Def", "abc Def ghi");
@@ -1,16 +1,16 @@
 use std::convert::TryFrom;
 
-pub(crate) type Score = i32;
+pub type Score = i32;
 
 pub(crate) const SCORE_STARTER: Score = 0;
 
 pub(crate) const SCORE_DEFAULT_BONUS: Score = 0;
 pub(crate) const SCORE_MAX: Score = Score::max_value();
 pub(crate) const SCORE_MIN: Score = Score::min_value();
-pub(crate) const SCORE_GAP_LEADING: Score = -1;
-pub(crate) const SCORE_GAP_TRAILING: Score = -1;
-pub(crate) const SCORE_GAP_INNER: Score = -2;
-pub(crate) const SCORE_MATCH_CONSECUTIVE: Score = 200;
+pub const SCORE_GAP_LEADING: Score = -1;
+pub const SCORE_GAP_TRAILING: Score = -1;
+pub const SCORE_GAP_INNER: Score = -2;
+pub const SCORE_MATCH_CONSECUTIVE: Score = 200;
 pub(crate) const SCORE_MATCH_SLASH: Score = 180;
 pub(crate) const SCORE_MATCH_WORD: Score = 160;
 pub(crate) const SCORE_MATCH_CAPITAL: Score = 140;
@@ -2,7 +2,26 @@ use structopt::clap::arg_enum;
 
 use source_item::{MatchTextFor, MatchType};
 
-use crate::MatchResult;
+use crate::{CaseMatching, MatchResult};
+
+/// A matching algorithm, which can be plugged into [`crate::Matcher`] via
+/// [`crate::Matcher::with_scorer`] for the custom algorithms.
+///
+/// The built-in algorithms are provided by [`Algo`]. A closure of
+/// `Fn(&str, &str, &CaseMatching) -> MatchResult` is also a `Scorer`.
+pub trait Scorer: Send + Sync {
+    /// Returns the score and the char indices of matched elements in `text` given `query`.
+    fn score(&self, query: &str, text: &str, case_matching: &CaseMatching) -> MatchResult;
+}
+
+impl<F> Scorer for F
+where
+    F: Fn(&str, &str, &CaseMatching) -> MatchResult + Send + Sync,
+{
+    fn score(&self, query: &str, text: &str, case_matching: &CaseMatching) -> MatchResult {
+        self(query, text, case_matching)
+    }
+}
 
 // Implement arg_enum for using it in the command line arguments.
 arg_enum! {
@@ -12,105 +31,563 @@ arg_enum! {
       Skim,
       Fzy,
       SubString,
+      Typo,
   }
 }
 
 impl Algo {
+    /// Returns the score and the char indices of matched elements in the whole line.
     pub fn apply_match<'a, T: MatchTextFor<'a>>(
         &self,
         query: &str,
         item: &T,
         match_type: &MatchType,
+        case_matching: &CaseMatching,
     ) -> MatchResult {
         item.match_text_for(match_type).and_then(|(text, offset)| {
-            let res = match self {
-                Self::Fzy => fzy::fuzzy_indices(text, query),
-                Self::Skim => skim::fuzzy_indices(text, query),
-                Self::SubString => substring::substr_indices(text, query),
-            };
-            res.map(|(score, indices)| (score, indices.into_iter().map(|x| x + offset).collect()))
+            self.apply_on_text(query, text, case_matching)
+                .map(|(score, indices)| (score, item.origin_indices(indices, offset)))
         })
     }
+
+    /// Returns the score and the char indices of matched elements in `text`.
+    pub fn apply_on_text(
+        &self,
+        query: &str,
+        text: &str,
+        case_matching: &CaseMatching,
+    ) -> MatchResult {
+        match self {
+            Self::Fzy => fzy::fuzzy_indices_with_case(text, query, case_matching),
+            Self::Skim => skim::fuzzy_indices_with_case(text, query, case_matching),
+            Self::SubString => substring::substr_indices_with_case(text, query, case_matching),
+            Self::Typo => typo::fuzzy_indices_with_case(text, query, case_matching),
+        }
+    }
+}
+
+impl Scorer for Algo {
+    fn score(&self, query: &str, text: &str, case_matching: &CaseMatching) -> MatchResult {
+        self.apply_on_text(query, text, case_matching)
+    }
 }
 
 pub mod skim {
-    use crate::MatchResult;
+    use crate::{CaseMatching, MatchResult};
     use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
 
     pub fn fuzzy_indices(text: &str, query: &str) -> MatchResult {
         SkimMatcherV2::default().fuzzy_indices(text, query)
     }
+
+    pub fn fuzzy_indices_with_case(
+        text: &str,
+        query: &str,
+        case_matching: &CaseMatching,
+    ) -> MatchResult {
+        let skim_matcher = match case_matching {
+            CaseMatching::Smart => SkimMatcherV2::default().smart_case(),
+            CaseMatching::Ignore => SkimMatcherV2::default().ignore_case(),
+            CaseMatching::Respect => SkimMatcherV2::default().respect_case(),
+        };
+        skim_matcher.fuzzy_indices(text, query)
+    }
 }
 
 pub mod fzy {
     // Reexport the fzy algorithm
     pub use extracted_fzy::*;
 
+    use crate::CaseMatching;
+
     /// Make the arguments order same to Skim's `fuzzy_indices()`.
     #[inline]
     pub fn fuzzy_indices(line: &str, query: &str) -> crate::MatchResult {
         match_and_score_with_positions(query, line).map(|(score, indices)| (score as i64, indices))
     }
+
+    #[inline]
+    pub fn fuzzy_indices_with_case(
+        line: &str,
+        query: &str,
+        case_matching: &CaseMatching,
+    ) -> crate::MatchResult {
+        match_and_score_with_case(query, line, case_matching.is_case_sensitive(query))
+            .map(|(score, indices)| (score as i64, indices))
+    }
 }
 
 pub mod substring {
-    fn find_start_at(slice: &str, start_at: usize, pat: &str) -> Option<usize> {
-        slice[start_at..].find(pat).map(|i| start_at + i)
+    //! Matches the whitespace separated parts of query as substrings in order.
+    //!
+    //! The score is calculated in the way of fzy for the matched positions so that it's
+    //! comparable to the other algorithms.
+
+    use crate::fzy::{
+        bonus_for_char, SCORE_GAP_INNER, SCORE_GAP_LEADING, SCORE_GAP_TRAILING,
+        SCORE_MATCH_CONSECUTIVE,
+    };
+
+    /// Maximum number of occurrences of each part of query to score, which bounds the work on
+    /// an unreasonably large haystack full of the occurrences.
+    const MAX_CANDIDATES: usize = 16;
+
+    /// Lowercases the chars so that the char indices are still valid for the origin text.
+    fn lowercase_per_char(chars: &[char]) -> Vec<char> {
+        chars
+            .iter()
+            .map(|c| c.to_lowercase().next().unwrap_or(*c))
+            .collect()
     }
 
-    fn substr_indices_impl(haystack: &str, niddle: &str) -> Option<(f64, Vec<usize>)> {
-        // unreasonably large haystack
-        if haystack.len() > 1024 {
-            return None;
-        }
+    /// Returns the start positions of `pat` in `haystack` from `start_at`.
+    fn find_start_at<'a>(
+        haystack: &'a [char],
+        start_at: usize,
+        pat: &'a [char],
+    ) -> impl Iterator<Item = usize> + 'a {
+        haystack[start_at.min(haystack.len())..]
+            .windows(pat.len())
+            .enumerate()
+            .filter(move |(_, window)| *window == pat)
+            .map(move |(idx, _)| start_at + idx)
+    }
 
-        let haystack = haystack.to_lowercase();
-        let haystack = haystack.as_str();
-
-        let mut offset = 0;
-        let mut positions = Vec::new();
-        for sub_niddle in niddle.split_whitespace() {
-            let sub_niddle = sub_niddle.to_lowercase();
-
-            match find_start_at(haystack, offset, &sub_niddle) {
-                Some(idx) => {
-                    offset = idx + sub_niddle.len();
-                    // For build without overflow checks this could be written as
-                    // `let mut pos = idx - 1;` with `|| { pos += 1; pos }` closure.
-                    let mut pos = idx;
-                    positions.resize_with(
-                        positions.len() + sub_niddle.len(),
-                        // Simple endless iterator for `idx..` range. Even though it's endless,
-                        // it will iterate only `sub_niddle.len()` times.
-                        || {
-                            pos += 1;
-                            pos - 1
-                        },
-                    );
-                }
-                None => return None,
+    fn substr_indices_impl(
+        haystack: &str,
+        niddle: &str,
+        case_sensitive: bool,
+    ) -> Option<(i64, Vec<usize>)> {
+        let origin = haystack.chars().collect::<Vec<_>>();
+
+        // Bonus of the word starts, camelCase humps and path separators.
+        let bonus = origin
+            .iter()
+            .scan('/', |prev, &ch| {
+                let bonus = bonus_for_char(*prev, ch) as i64;
+                *prev = ch;
+                Some(bonus)
+            })
+            .collect::<Vec<_>>();
+
+        let to_matchable = |chars: Vec<char>| {
+            if case_sensitive {
+                chars
+            } else {
+                lowercase_per_char(&chars)
             }
+        };
+
+        let haystack = to_matchable(origin);
+
+        let sub_niddles = niddle
+            .split_whitespace()
+            .map(|sub_niddle| to_matchable(sub_niddle.chars().collect()))
+            .collect::<Vec<_>>();
+
+        if sub_niddles.is_empty() {
+            return Some((0, Vec::new()));
         }
 
-        if positions.is_empty() {
-            return Some((0f64, positions));
+        // The leftmost match of the preceding parts gives the earliest position each part can
+        // start at, the candidates of every part are searched from there so that a line is never
+        // rejected as long as there is a match in order.
+        let mut candidates = Vec::with_capacity(sub_niddles.len());
+        let mut min_start = 0;
+        for sub_niddle in &sub_niddles {
+            let found = find_start_at(&haystack, min_start, sub_niddle)
+                .take(MAX_CANDIDATES)
+                .collect::<Vec<_>>();
+            min_start = *found.first()? + sub_niddle.len();
+            candidates.push(found);
         }
 
-        let last_pos = positions.last().unwrap();
-        let match_len = (last_pos + 1 - positions[0]) as f64;
+        // Pick the best combination of candidates in order, `best[i][j]` is the best score of the
+        // first `i + 1` parts ending with the `j`-th candidate of part `i`, together with the
+        // index of the candidate of the previous part.
+        let mut best: Vec<Vec<Option<(i64, usize)>>> = Vec::with_capacity(candidates.len());
+        for (i, (sub_niddle, found)) in sub_niddles.iter().zip(candidates.iter()).enumerate() {
+            let consecutive = (sub_niddle.len() - 1) as i64 * SCORE_MATCH_CONSECUTIVE as i64;
+            let scores = found
+                .iter()
+                .map(|&idx| {
+                    if i == 0 {
+                        let gap = idx as i64 * SCORE_GAP_LEADING as i64;
+                        return Some((bonus[idx] + gap + consecutive, 0));
+                    }
+                    let prev_len = sub_niddles[i - 1].len();
+                    candidates[i - 1]
+                        .iter()
+                        .zip(best[i - 1].iter())
+                        .enumerate()
+                        .filter_map(|(prev, (&prev_idx, prev_best))| {
+                            let (prev_score, _) = (*prev_best)?;
+                            let prev_end = prev_idx + prev_len;
+                            if prev_end > idx {
+                                return None;
+                            }
+                            let gap = (idx - prev_end) as i64 * SCORE_GAP_INNER as i64;
+                            Some((prev_score + bonus[idx] + gap + consecutive, prev))
+                        })
+                        .fold(None, |acc: Option<(i64, usize)>, x| match acc {
+                            Some(acc) if acc.0 >= x.0 => Some(acc),
+                            _ => Some(x),
+                        })
+                })
+                .collect();
+            best.push(scores);
+        }
+
+        let last = sub_niddles.len() - 1;
+        let last_len = sub_niddles[last].len();
+        let (score, mut j) = candidates[last]
+            .iter()
+            .zip(best[last].iter())
+            .enumerate()
+            .filter_map(|(j, (&idx, last_best))| {
+                let (score, _) = (*last_best)?;
+                let trailing = haystack.len() - idx - last_len;
+                Some((score + trailing as i64 * SCORE_GAP_TRAILING as i64, j))
+            })
+            .fold(None, |acc: Option<(i64, usize)>, x| match acc {
+                Some(acc) if acc.0 >= x.0 => Some(acc),
+                _ => Some(x),
+            })?;
+
+        let mut starts = vec![0; sub_niddles.len()];
+        for i in (0..sub_niddles.len()).rev() {
+            starts[i] = candidates[i][j];
+            if let Some((_, prev)) = best[i][j] {
+                j = prev;
+            }
+        }
+
+        let positions = starts
+            .iter()
+            .zip(sub_niddles.iter())
+            .flat_map(|(&start, sub_niddle)| start..start + sub_niddle.len())
+            .collect::<Vec<_>>();
+
+        // perfect match
+        if positions.len() == haystack.len() {
+            return Some((crate::fzy::Score::MAX as i64, positions));
+        }
 
-        Some((
-            (2f64 / (positions[0] + 1) as f64) + 1f64 / (last_pos + 1) as f64 - match_len,
-            positions,
-        ))
+        Some((score, positions))
     }
 
+    /// Matches case-insensitively.
     pub fn substr_indices(haystack: &str, niddle: &str) -> Option<(i64, Vec<usize>)> {
-        substr_indices_impl(haystack, niddle).map(|(score, positions)| (score as i64, positions))
+        substr_indices_impl(haystack, niddle, false)
+    }
+
+    pub fn substr_indices_with_case(
+        haystack: &str,
+        niddle: &str,
+        case_matching: &crate::CaseMatching,
+    ) -> Option<(i64, Vec<usize>)> {
+        substr_indices_impl(haystack, niddle, case_matching.is_case_sensitive(niddle))
     }
 
     #[test]
     fn test_substr() {
-        println!("{:?}", substr_indices("sr bl", "src/bun/blune"));
+        assert_eq!(
+            substr_indices("src/bun/blune", "sr bl").unwrap().1,
+            vec![0, 1, 8, 9]
+        );
+
+        // Prefer the word start, camelCase hump and path segment to the first occurrence.
+        let cases = vec![
+            ("unmatched_match", "match", 10),
+            ("rematchMatch", "match", 7),
+            ("crates/reformat/format.rs", "format", 16),
+        ];
+        for (haystack, niddle, start) in cases {
+            assert_eq!(substr_indices(haystack, niddle).unwrap().1[0], start);
+        }
+
+        // A part preferring a later occurrence doesn't reject the match in order.
+        assert_eq!(
+            substr_indices("xfoo_bar /foo", "foo bar").unwrap().1,
+            vec![1, 2, 3, 5, 6, 7]
+        );
+        // The parts are matched in order.
+        assert_eq!(substr_indices("src/lib.rs", "lib src"), None);
+        assert_eq!(
+            substr_indices("src/lib.rs/src", "lib src").unwrap().1,
+            vec![4, 5, 6, 11, 12, 13]
+        );
+
+        // Comparable to fzy.
+        assert_eq!(
+            substr_indices("src/files.rs", "file"),
+            crate::fzy::fuzzy_indices("src/files.rs", "file")
+        );
+
+        // The whole long line is searched.
+        let long_line = format!("needle {}", "x".repeat(4096));
+        assert_eq!(
+            substr_indices(&long_line, "needle").unwrap().1,
+            vec![0, 1, 2, 3, 4, 5]
+        );
+        let long_line = format!("{} needle", "x".repeat(4096));
+        assert_eq!(
+            substr_indices(&long_line, "needle").unwrap().1,
+            (4097..4103).collect::<Vec<_>>()
+        );
+    }
+}
+
+/// Fuzzy matching that tolerates a few typos in the query.
+///
+/// Missed letters, wrong letters and the transposition of two adjacent letters are counted as
+/// one edit each, at most [`typo::MAX_TYPOS`] edits are allowed and each one is penalized.
+pub mod typo {
+    use crate::{CaseMatching, MatchResult};
+
+    /// Maximum number of typos tolerated in a query.
+    pub const MAX_TYPOS: usize = 2;
+
+    /// Penalty of one typo, same to the score of a consecutive match in fzy.
+    const TYPO_PENALTY: i64 = 200;
+
+    /// unreasonably large haystack for the edit distance table.
+    const MAX_TEXT_LEN: usize = 1024;
+
+    /// Returns the number of typos allowed for a query of `query_len` chars.
+    ///
+    /// One typo for the short queries and none for the queries of 1 or 2 chars, which would
+    /// match almost everything otherwise.
+    fn allowed_typos(query_len: usize) -> usize {
+        match query_len {
+            0..=2 => 0,
+            3..=7 => 1,
+            _ => MAX_TYPOS,
+        }
+    }
+
+    /// Returns true if `query[i - 2..i]` is transposed in `text[j - 2..j]`.
+    fn is_transposed(query: &[char], text: &[char], i: usize, j: usize) -> bool {
+        i >= 2
+            && j >= 2
+            && query[i - 1] != query[i - 2]
+            && query[i - 1] == text[j - 2]
+            && query[i - 2] == text[j - 1]
+    }
+
+    /// Returns the least edits of the cell `(i, j)` given the previous two rows and the current
+    /// row filled up to `j - 1`.
+    fn edits_at(
+        query: &[char],
+        text: &[char],
+        (i, j): (usize, usize),
+        (prev2, prev, cur): (&[usize], &[usize], &[usize]),
+    ) -> usize {
+        let mut least = cur[j - 1].min(prev[j] + 1);
+        if query[i - 1] == text[j - 1] {
+            least = least.min(prev[j - 1]);
+        } else {
+            least = least.min(prev[j - 1] + 1);
+        }
+        if is_transposed(query, text, i, j) {
+            least = least.min(prev2[j - 2] + 1);
+        }
+        least
+    }
+
+    /// Returns the least edits to match `query` within `text` if they are no more than
+    /// `max_typos`, only three rows of the edit distance table are kept.
+    fn count_edits(query: &[char], text: &[char], max_typos: usize) -> Option<usize> {
+        let m = text.len();
+
+        // Row `i`: the least edits to match query[..i] within text[..j].
+        let mut prev2 = vec![0usize; m + 1];
+        let mut prev = vec![0usize; m + 1];
+        let mut cur = vec![0usize; m + 1];
+        let mut prev_min = 0;
+
+        for i in 1..=query.len() {
+            cur[0] = i;
+            for j in 1..=m {
+                cur[j] = edits_at(query, text, (i, j), (&prev2, &prev, &cur));
+            }
+
+            // The least edits of a row is no less than the minimum of the previous row and the
+            // one before it plus 1, no way to get back under `max_typos` from here.
+            let cur_min = *cur.iter().min()?;
+            if cur_min > max_typos && prev_min >= max_typos {
+                return None;
+            }
+            prev_min = cur_min;
+
+            std::mem::swap(&mut prev2, &mut prev);
+            std::mem::swap(&mut prev, &mut cur);
+        }
+
+        Some(prev[m]).filter(|&edits| edits <= max_typos)
+    }
+
+    /// Returns the char indices of `text` aligned with `query` by the least edits.
+    fn align(query: &[char], text: &[char]) -> Vec<usize> {
+        let (n, m) = (query.len(), text.len());
+
+        let mut edits = vec![vec![0usize; m + 1]; n + 1];
+        for i in 1..=n {
+            edits[i][0] = i;
+            for j in 1..=m {
+                let value = edits_at(
+                    query,
+                    text,
+                    (i, j),
+                    (&edits[i.saturating_sub(2)], &edits[i - 1], &edits[i]),
+                );
+                edits[i][j] = value;
+            }
+        }
+
+        // Backtrack from the leftmost end where the last char of query is aligned with the least
+        // edits, which keeps the alignment compact.
+        let least = edits[n][m];
+        let is_aligned_end = |j: usize| {
+            let diff = if query[n - 1] == text[j - 1] { 0 } else { 1 };
+            edits[n - 1][j - 1] + diff == least
+                || (is_transposed(query, text, n, j) && edits[n - 2][j - 2] + 1 == least)
+        };
+        let end = (1..=m)
+            .find(|&j| edits[n][j] == least && is_aligned_end(j))
+            .unwrap_or(m);
+
+        let mut indices = Vec::with_capacity(n);
+        let (mut i, mut j) = (n, end);
+        while i > 0 && j > 0 {
+            if query[i - 1] == text[j - 1] && edits[i][j] == edits[i - 1][j - 1] {
+                indices.push(j - 1);
+                i -= 1;
+                j -= 1;
+            } else if is_transposed(query, text, i, j) && edits[i][j] == edits[i - 2][j - 2] + 1 {
+                indices.push(j - 1);
+                indices.push(j - 2);
+                i -= 2;
+                j -= 2;
+            } else if query[i - 1] != text[j - 1] && edits[i][j] == edits[i - 1][j - 1] + 1 {
+                // Substituted, prefer it to dropping the char of query so that the position
+                // is still highlighted.
+                indices.push(j - 1);
+                i -= 1;
+                j -= 1;
+            } else if edits[i][j] == edits[i][j - 1] {
+                j -= 1;
+            } else {
+                i -= 1;
+            }
+        }
+        indices.reverse();
+
+        indices
+    }
+
+    /// Scores the aligned positions in the way of fzy so that it's comparable to fzy.
+    fn score_positions(text: &[char], positions: &[usize]) -> i64 {
+        use crate::fzy::{
+            bonus_for_char, SCORE_GAP_INNER, SCORE_GAP_LEADING, SCORE_GAP_TRAILING,
+            SCORE_MATCH_CONSECUTIVE,
+        };
+
+        let mut score = 0i64;
+        let mut last: Option<usize> = None;
+        for &pos in positions {
+            score += match last {
+                Some(last) if pos == last + 1 => SCORE_MATCH_CONSECUTIVE as i64,
+                Some(last) => {
+                    let prev = text[pos - 1];
+                    bonus_for_char(prev, text[pos]) as i64
+                        + (pos - last - 1) as i64 * SCORE_GAP_INNER as i64
+                }
+                None => {
+                    let prev = if pos == 0 { '/' } else { text[pos - 1] };
+                    bonus_for_char(prev, text[pos]) as i64 + pos as i64 * SCORE_GAP_LEADING as i64
+                }
+            };
+            last = Some(pos);
+        }
+        if let Some(last) = last {
+            score += (text.len() - last - 1) as i64 * SCORE_GAP_TRAILING as i64;
+        }
+        score
+    }
+
+    pub fn fuzzy_indices_with_case(
+        text: &str,
+        query: &str,
+        case_matching: &CaseMatching,
+    ) -> MatchResult {
+        if let Some(ret) = crate::fzy::fuzzy_indices_with_case(text, query, case_matching) {
+            return Some(ret);
+        }
+
+        let query_len = query.chars().count();
+        let max_typos = allowed_typos(query_len);
+        if max_typos == 0 || text.chars().count() > MAX_TEXT_LEN {
+            return None;
+        }
+
+        let to_chars = |s: &str| -> Vec<char> {
+            if case_matching.is_case_sensitive(query) {
+                s.chars().collect()
+            } else {
+                s.chars()
+                    .map(|c| c.to_lowercase().next().unwrap_or(c))
+                    .collect()
+            }
+        };
+
+        let (query, chars) = (to_chars(query), to_chars(text));
+        let typos = count_edits(&query, &chars, max_typos)?;
+        let indices = align(&query, &chars);
+        if indices.is_empty() {
+            return None;
+        }
+
+        let score = score_positions(&chars, &indices) - TYPO_PENALTY * typos as i64;
+        Some((score, indices))
+    }
+
+    #[test]
+    fn test_typo_tolerance() {
+        let fuzzy_indices =
+            |text, query| fuzzy_indices_with_case(text, query, &CaseMatching::Smart);
+
+        // Transposition.
+        assert!(crate::fzy::fuzzy_indices("fn receive_message()", "recieve").is_none());
+        assert!(fuzzy_indices("fn receive_message()", "recieve").is_some());
+        // Substitution.
+        assert!(crate::fzy::fuzzy_indices("pub fn function_name()", "fumction").is_none());
+        let (_, indices) = fuzzy_indices("pub fn function_name()", "fumction").unwrap();
+        assert_eq!(indices, vec![7, 8, 9, 10, 11, 12, 13, 14]);
+        // Wrong letters.
+        assert!(crate::fzy::fuzzy_indices("clap#provider#files", "prowidar").is_none());
+        assert!(fuzzy_indices("clap#provider#files", "prowidar").is_some());
+        // Too many typos.
+        assert!(fuzzy_indices("clap#provider#files", "priwodar").is_none());
+        // One typo in the short query.
+        assert!(crate::fzy::fuzzy_indices("function", "fcu").is_none());
+        assert!(fuzzy_indices("function", "fcu").is_some());
+        assert!(fuzzy_indices("function", "fcux").is_none());
+        // No typo is allowed in the query of 2 chars.
+        assert!(fuzzy_indices("function", "fx").is_none());
+
+        let (typo_score, indices) = fuzzy_indices("pub fn function_name()", "fucntion").unwrap();
+        let (exact_score, _) = fuzzy_indices("pub fn function_name()", "function").unwrap();
+        assert!(exact_score > typo_score);
+        assert_eq!(indices, vec![7, 8, 9, 10, 11, 12, 13, 14]);
+
+        // The typo covers the whole text.
+        let (whole_score, indices) = fuzzy_indices("receive", "recieve").unwrap();
+        assert_eq!(indices, (0..7).collect::<Vec<_>>());
+        assert_eq!(fuzzy_indices("abc", "abd").unwrap().1, vec![0, 1, 2]);
+        let (partial_score, indices) = fuzzy_indices("receive_message", "recieve").unwrap();
+        assert_eq!(indices, (0..7).collect::<Vec<_>>());
+        assert!(whole_score > partial_score);
+        assert!(partial_score > 0);
     }
 }
@@ -0,0 +1,304 @@
+//! Bonuses added to the score of base matching algorithm.
+//!
+//! Multiple bonuses can be stacked, each one is calculated from the base score and then
+//! multiplied by its weight.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use crate::Score;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub enum Bonus {
+    /// Give a bonus if the needle matches in the basename of the haystack.
+    ///
+    /// Ref https://github.com/liuchengxu/vim-clap/issues/561
+    FileName,
+
+    /// Give a bonus to the shallower paths.
+    PathDepth,
+
+    /// Give a bonus to the recently modified files, the relative paths are resolved against
+    /// the inner directory.
+    RecentlyModified(PathBuf),
+
+    /// Give a bonus to the files sharing a longer directory prefix with `buffer_path`, usually
+    /// the current buffer, the relative paths are resolved against `cwd`.
+    Proximity { cwd: PathBuf, buffer_path: PathBuf },
+
+    /// Give a bonus to the files with one of the extensions, the former the more preferred.
+    Extension(Vec<String>),
+
+    /// No additional bonus.
+    None,
+}
+
+impl Default for Bonus {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl From<String> for Bonus {
+    fn from(b: String) -> Self {
+        b.as_str().into()
+    }
+}
+
+impl From<&str> for Bonus {
+    fn from(b: &str) -> Self {
+        b.parse::<WeightedBonus>()
+            .map(|weighted| weighted.bonus)
+            .unwrap_or_default()
+    }
+}
+
+/// Environment used to fill the arguments of bonuses.
+#[derive(Debug, Clone, Default)]
+pub struct BonusContext {
+    /// Working directory the relative paths are resolved against.
+    pub cwd: PathBuf,
+    /// The default path of `proximity` bonus.
+    pub buffer_path: Option<PathBuf>,
+}
+
+impl BonusContext {
+    pub fn new(cwd: PathBuf, buffer_path: Option<PathBuf>) -> Self {
+        Self { cwd, buffer_path }
+    }
+}
+
+/// A bonus with its weight.
+#[derive(Debug, Clone)]
+pub struct WeightedBonus {
+    pub bonus: Bonus,
+    pub weight: f64,
+}
+
+impl From<Bonus> for WeightedBonus {
+    fn from(bonus: Bonus) -> Self {
+        Self { bonus, weight: 1.0 }
+    }
+}
+
+impl FromStr for WeightedBonus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+        Self::parse(s, &BonusContext::new(cwd, None))
+    }
+}
+
+impl WeightedBonus {
+    /// Parses the bonus in the form of `name[=arg][*weight]`, e.g., `filename`,
+    /// `pathdepth*0.5`, `extension=rs,toml*2`, `proximity=src/main.rs`.
+    pub fn parse(s: &str, ctx: &BonusContext) -> Result<Self, String> {
+        let (spec, weight) = match s.rfind('*') {
+            Some(pos) => (
+                &s[..pos],
+                s[pos + 1..]
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|_| format!("Invalid bonus weight: {:?}", s))?,
+            ),
+            None => (s, 1.0),
+        };
+
+        let (name, arg) = match spec.find('=') {
+            Some(pos) => (&spec[..pos], Some(spec[pos + 1..].trim())),
+            None => (spec, None),
+        };
+
+        let resolve = |path: &str| ctx.cwd.join(path);
+
+        let bonus = match name.trim().to_lowercase().as_str() {
+            "filename" => Bonus::FileName,
+            "pathdepth" => Bonus::PathDepth,
+            "recentlymodified" => {
+                Bonus::RecentlyModified(arg.map(resolve).unwrap_or_else(|| ctx.cwd.clone()))
+            }
+            "proximity" => Bonus::Proximity {
+                cwd: ctx.cwd.clone(),
+                buffer_path: arg
+                    .map(resolve)
+                    .or_else(|| ctx.buffer_path.clone())
+                    .ok_or_else(|| format!("Missing the buffer path of bonus: {:?}", s))?,
+            },
+            "extension" => Bonus::Extension(
+                arg.ok_or_else(|| format!("Missing the extensions of bonus: {:?}", s))?
+                    .split(',')
+                    .map(|ext| ext.trim().trim_start_matches('.').to_string())
+                    .filter(|ext| !ext.is_empty())
+                    .collect(),
+            ),
+            "none" => Bonus::None,
+            _ => return Err(format!("Unknown bonus: {:?}", s)),
+        };
+
+        Ok(Self { bonus, weight })
+    }
+
+    /// Returns the weighted bonus score.
+    pub fn calculate(&self, raw: &str, score: Score, indices: &[usize]) -> Score {
+        (calculate_bonus(&self.bonus, raw, score, indices) as f64 * self.weight) as Score
+    }
+}
+
+/// Returns the number of directories in `path`.
+fn path_depth(path: &str) -> usize {
+    Path::new(path).components().count().saturating_sub(1)
+}
+
+/// Returns the ratio of the directory of `path` shared with the directory of `buffer_path`,
+/// the common ancestors of `cwd` are not counted.
+///
+/// 1.0 if they are in the same directory, 0.0 if they share nothing below `cwd`.
+fn proximity_ratio(cwd: &Path, buffer_path: &Path, path: &Path) -> f64 {
+    let (buffer_dir, dir) = match (buffer_path.parent(), path.parent()) {
+        (Some(buffer_dir), Some(dir)) => (buffer_dir, dir),
+        _ => return 0.0,
+    };
+
+    if buffer_dir == dir {
+        return 1.0;
+    }
+
+    let base = cwd.components().count();
+    let buffer_depth = buffer_dir.components().count();
+    if !buffer_dir.starts_with(cwd) || buffer_depth <= base {
+        return 0.0;
+    }
+
+    let shared = buffer_dir
+        .components()
+        .zip(dir.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    shared.saturating_sub(base) as f64 / (buffer_depth - base) as f64
+}
+
+/// Calculates the bonus score given the match result of base algorithm on the `raw` line.
+pub fn calculate_bonus(bonus: &Bonus, raw: &str, score: Score, indices: &[usize]) -> Score {
+    match bonus {
+        Bonus::FileName => {
+            if let Some((_, idx)) = pattern::file_name_only(raw) {
+                let idx = raw[..idx].chars().count();
+                let total = raw.chars().count();
+                let hits = indices.iter().filter(|x| **x >= idx).collect::<Vec<_>>();
+                if total > idx {
+                    // bonus = base_score * len(matched elements in filename) / len(filename)
+                    score * hits.len() as i64 / (total - idx) as i64
+                } else {
+                    0
+                }
+            } else {
+                0
+            }
+        }
+        Bonus::PathDepth => {
+            // bonus = base_score / (depth + 2)
+            score / (path_depth(raw) as i64 + 2)
+        }
+        Bonus::RecentlyModified(cwd) => {
+            let age = std::fs::metadata(cwd.join(raw))
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+            match age {
+                Some(age) if age < DAY => score / 2,
+                Some(age) if age < DAY * 7 => score / 4,
+                Some(age) if age < DAY * 30 => score / 8,
+                _ => 0,
+            }
+        }
+        Bonus::Proximity { cwd, buffer_path } => {
+            // bonus = base_score * proximity_ratio / 2
+            let ratio = proximity_ratio(cwd, buffer_path, &cwd.join(raw));
+            (score as f64 * ratio / 2.0) as Score
+        }
+        Bonus::Extension(extensions) => Path::new(raw)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| extensions.iter().position(|x| x == ext))
+            .map(|pos| {
+                // bonus = base_score * (total - pos) / (2 * total)
+                let total = extensions.len() as i64;
+                score * (total - pos as i64) / (2 * total)
+            })
+            .unwrap_or(0),
+        Bonus::None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bonus() {
+        let ctx = BonusContext::new("/repo".into(), Some("/repo/src/lib.rs".into()));
+        let parse = |s: &str| WeightedBonus::parse(s, &ctx).unwrap();
+
+        assert!(matches!(parse("FileName").bonus, Bonus::FileName));
+        assert_eq!(parse("pathdepth*0.5").weight, 0.5);
+        assert!(matches!(
+            parse("extension=rs, .toml*2"),
+            WeightedBonus { bonus: Bonus::Extension(ref exts), weight } if exts == &["rs", "toml"] && weight == 2.0
+        ));
+        assert!(matches!(
+            parse("proximity").bonus,
+            Bonus::Proximity { ref buffer_path, .. } if buffer_path == Path::new("/repo/src/lib.rs")
+        ));
+        assert!(matches!(
+            parse("recentlymodified").bonus,
+            Bonus::RecentlyModified(ref cwd) if cwd == Path::new("/repo")
+        ));
+
+        assert!(WeightedBonus::parse("extension", &ctx).is_err());
+        assert!(WeightedBonus::parse("filename*x", &ctx).is_err());
+        assert!(WeightedBonus::parse("unknown", &ctx).is_err());
+        assert!(WeightedBonus::parse("proximity", &BonusContext::default()).is_err());
+    }
+
+    #[test]
+    fn test_path_bonus() {
+        let depth = Bonus::PathDepth;
+        assert!(
+            calculate_bonus(&depth, "lib.rs", 100, &[])
+                > calculate_bonus(&depth, "src/lib.rs", 100, &[])
+        );
+
+        let proximity = Bonus::Proximity {
+            cwd: "/repo".into(),
+            buffer_path: "/repo/src/lib.rs".into(),
+        };
+        assert_eq!(calculate_bonus(&proximity, "src/mod.rs", 100, &[]), 50);
+        assert_eq!(calculate_bonus(&proximity, "tests/mod.rs", 100, &[]), 0);
+
+        let proximity = Bonus::Proximity {
+            cwd: "/repo".into(),
+            buffer_path: "/repo/crates/stdio_server/src/session/context.rs".into(),
+        };
+        let bonuses = vec![
+            "crates/stdio_server/src/session/mod.rs",
+            "crates/stdio_server/src/lib.rs",
+            "crates/matcher/src/lib.rs",
+            "src/main.rs",
+            "/elsewhere/crates/stdio_server/src/session/mod.rs",
+        ]
+        .into_iter()
+        .map(|path| calculate_bonus(&proximity, path, 100, &[]))
+        .collect::<Vec<_>>();
+        assert_eq!(bonuses, vec![50, 37, 12, 0, 0]);
+
+        let extension = Bonus::Extension(vec!["rs".into(), "toml".into()]);
+        assert_eq!(calculate_bonus(&extension, "src/lib.rs", 100, &[]), 50);
+        assert_eq!(calculate_bonus(&extension, "Cargo.toml", 100, &[]), 25);
+        assert_eq!(calculate_bonus(&extension, "README.md", 100, &[]), 0);
+    }
+}
@@ -2,6 +2,9 @@
 //!
 //! The matcher result consists of the score and the indices of matched items.
 //!
+//! The indices of matched items are always char indices(`str::chars()`) of the whole line, no
+//! matter which `Algo` and `MatchType` are used, combining characters are counted separately.
+//!
 //! There two steps to match a line:
 //!
 //! //     RawLine
@@ -11,115 +14,257 @@
 //! //        ↓
 //! //    MatchText
 //! //        |
-//! //        |      Algo: run the match algorithm on MatchText.
+//! //        |    Scorer: run the match algorithm on MatchText.
 //! //        |
 //! //        ↓
 //! //   MatchResult
 //!
 
 mod algo;
+mod bonus;
+mod normalize;
+
+use std::cmp::Ordering;
+use std::path::Path;
 
-use source_item::SourceItem;
+use source_item::{MatchTextFor, SourceItem};
 use structopt::clap::arg_enum;
 
 pub use algo::*;
+pub use bonus::{calculate_bonus, Bonus, BonusContext, WeightedBonus};
+pub use normalize::{normalize, NormalizedText};
 pub use source_item::MatchType;
 
 pub type Score = i64;
 
 /// A tuple of (score, matched_indices) for the line has a match given the query string.
+///
+/// `matched_indices` are the char indices of matched elements.
 pub type MatchResult = Option<(Score, Vec<usize>)>;
 
-/// Calculates the bonus score given the match result of base algorithm.
-pub fn calculate_bonus(bonus: &Bonus, item: &SourceItem, score: Score, indices: &[usize]) -> Score {
-    match bonus {
-        Bonus::FileName => {
-            if let Some((_, idx)) = pattern::file_name_only(&item.raw) {
-                let hits = indices.iter().filter(|x| **x >= idx).collect::<Vec<_>>();
-                if item.raw.len() > idx {
-                    // bonus = base_score * len(matched elements in filename) / len(filename)
-                    let bonus = score * hits.len() as i64 / (item.raw.len() - idx) as i64;
-                    bonus
-                } else {
-                    0
-                }
-            } else {
-                0
-            }
-        }
-        Bonus::None => 0,
-    }
-}
-
 arg_enum! {
-  #[derive(Debug, Clone)]
-  pub enum Bonus {
-      // Give a bonus if the needle matches in the basename of the haystack.
-      //
-      // Ref https://github.com/liuchengxu/vim-clap/issues/561
-      FileName,
-
-      // No additional bonus.
-      None,
+  /// Case sensitivity of the matching, honoured by all the `Algo`s.
+  #[derive(Debug, Clone, PartialEq)]
+  pub enum CaseMatching {
+      // Case sensitive if the query contains any uppercase char.
+      Smart,
+
+      // Always case insensitive.
+      Ignore,
+
+      // Always case sensitive.
+      Respect,
   }
 }
 
-impl Default for Bonus {
+impl Default for CaseMatching {
     fn default() -> Self {
-        Self::None
+        Self::Smart
+    }
+}
+
+impl From<String> for CaseMatching {
+    fn from(case_matching: String) -> Self {
+        case_matching.as_str().into()
     }
 }
 
-impl From<String> for Bonus {
-    fn from(b: String) -> Self {
-        b.as_str().into()
+impl From<&str> for CaseMatching {
+    fn from(case_matching: &str) -> Self {
+        match case_matching.to_lowercase().as_str() {
+            "ignore" => Self::Ignore,
+            "respect" => Self::Respect,
+            _ => Self::Smart,
+        }
     }
 }
 
-impl From<&str> for Bonus {
-    fn from(b: &str) -> Self {
-        match b.to_lowercase().as_str() {
-            "none" => Self::None,
-            "filename" => Self::FileName,
-            _ => Self::None,
+impl CaseMatching {
+    /// Returns true if the matching should be case sensitive given the query.
+    pub fn is_case_sensitive(&self, query: &str) -> bool {
+        match self {
+            Self::Smart => query.chars().any(|c| c.is_uppercase()),
+            Self::Ignore => false,
+            Self::Respect => true,
+        }
+    }
+}
+
+arg_enum! {
+  /// Rule to rank the items with the same score.
+  ///
+  /// The items are always in the original order if all the tiebreakers are equal.
+  #[derive(Debug, Clone, PartialEq)]
+  pub enum Tiebreak {
+      // Prefer the shorter display text.
+      Length,
+      // Prefer the earlier start of match.
+      Begin,
+      // Prefer the earlier item in the source, the rest tiebreakers are ignored.
+      Index,
+      // Prefer the shallower path.
+      PathDepth,
+  }
+}
+
+impl Tiebreak {
+    /// Compares two items with the same score, `Ordering::Less` means `a` should rank higher.
+    pub fn compare(
+        &self,
+        (a, a_indices): (&SourceItem, &[usize]),
+        (b, b_indices): (&SourceItem, &[usize]),
+    ) -> Ordering {
+        match self {
+            Self::Length => a
+                .display_text()
+                .chars()
+                .count()
+                .cmp(&b.display_text().chars().count()),
+            Self::Begin => match (a_indices.first(), b_indices.first()) {
+                (Some(a), Some(b)) => a.cmp(b),
+                (a, b) => b.is_none().cmp(&a.is_none()),
+            },
+            Self::Index => Ordering::Equal,
+            Self::PathDepth => Path::new(&a.raw)
+                .components()
+                .count()
+                .cmp(&Path::new(&b.raw).components().count()),
         }
     }
 }
 
-/// `Matcher` is composed of two components:
+/// `Matcher` is composed of these components:
 ///
 ///   * `match_type`: represents the way of extracting the matching piece from the raw line.
-///   * `algo`: algorithm used for matching the text.
-///   * `bonus`: add a bonus to the result of base `algo`.
+///   * `scorer`: algorithm used for matching the text, one of [`Algo`] or a custom [`Scorer`].
+///   * `bonuses`: add the weighted bonuses to the result of base `scorer` in sequence.
+///   * `case_matching`: case sensitivity of `scorer`.
+///   * `normalize`: fold the diacritics and compatibility forms before running `scorer`.
+///   * `tiebreaks`: rank the items with the same score in sequence.
 pub struct Matcher {
     match_type: MatchType,
-    algo: Algo,
-    bonus: Bonus,
+    scorer: Box<dyn Scorer>,
+    bonuses: Vec<WeightedBonus>,
+    case_matching: CaseMatching,
+    normalize: bool,
+    tiebreaks: Vec<Tiebreak>,
 }
 
 impl Matcher {
     /// Constructs a `Matcher`.
-    pub fn new(algo: Algo, match_type: MatchType, bonus: Bonus) -> Self {
+    pub fn new(
+        algo: Algo,
+        match_type: MatchType,
+        bonus: Bonus,
+        case_matching: CaseMatching,
+    ) -> Self {
         Self {
-            algo,
+            scorer: Box::new(algo),
             match_type,
-            bonus,
+            bonuses: vec![bonus.into()],
+            case_matching,
+            normalize: false,
+            tiebreaks: Vec::new(),
         }
     }
 
+    /// Replaces the match algorithm with a built-in one.
+    pub fn with_algo(self, algo: Algo) -> Self {
+        self.with_scorer(algo)
+    }
+
+    /// Replaces the match algorithm with a custom one.
+    pub fn with_scorer<S: Scorer + 'static>(mut self, scorer: S) -> Self {
+        self.scorer = Box::new(scorer);
+        self
+    }
+
+    /// Replaces the bonuses.
+    pub fn with_bonuses(mut self, bonuses: Vec<WeightedBonus>) -> Self {
+        self.bonuses = bonuses;
+        self
+    }
+
+    /// Replaces the tiebreakers.
+    pub fn with_tiebreaks(mut self, tiebreaks: Vec<Tiebreak>) -> Self {
+        self.tiebreaks = tiebreaks;
+        self
+    }
+
+    /// Enables the diacritics- and width-insensitive matching.
+    ///
+    /// The matched indices are still the char indices of the origin text.
+    pub fn with_normalization(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
     /// Match the item without considering the bonus.
     #[inline]
     pub fn base_match(&self, item: &SourceItem, query: &str) -> MatchResult {
-        self.algo.apply_match(query, item, &self.match_type)
+        let (text, offset) = item.match_text_for(&self.match_type)?;
+        self.match_text(text, query)
+            .map(|(score, indices)| (score, item.origin_indices(indices, offset)))
+    }
+
+    /// Returns the score and the char indices of matched elements in `text`.
+    fn match_text(&self, text: &str, query: &str) -> MatchResult {
+        if self.normalize {
+            let query = normalize(query);
+            let normalized = normalize(text);
+            self.scorer
+                .score(&query.text, &normalized.text, &self.case_matching)
+                .map(|(score, indices)| (score, normalized.origin_indices(indices)))
+        } else {
+            self.scorer.score(query, text, &self.case_matching)
+        }
+    }
+
+    /// Compares two match results, `Ordering::Less` means `a` should rank higher.
+    ///
+    /// The higher score ranks higher, then the tiebreakers are applied. It should be used with a
+    /// stable sort so that the items are in the original order if they are all equal.
+    pub fn compare_results(
+        &self,
+        (a, a_score, a_indices): (&SourceItem, Score, &[usize]),
+        (b, b_score, b_indices): (&SourceItem, Score, &[usize]),
+    ) -> Ordering {
+        b_score.cmp(&a_score).then_with(|| {
+            for tiebreak in &self.tiebreaks {
+                if *tiebreak == Tiebreak::Index {
+                    break;
+                }
+                match tiebreak.compare((a, a_indices), (b, b_indices)) {
+                    Ordering::Equal => continue,
+                    ord => return ord,
+                }
+            }
+            Ordering::Equal
+        })
     }
 
     /// Actually performs the matching algorithm.
     pub fn do_match(&self, item: &SourceItem, query: &str) -> MatchResult {
-        self.base_match(item, query).map(|(score, indices)| {
-            let bonus_score = calculate_bonus(&self.bonus, item, score, &indices);
-            (score + bonus_score, indices)
+        self.base_match(item, query)
+            .map(|(score, indices)| self.add_bonuses(&item.raw, score, indices))
+    }
+
+    /// Performs the matching algorithm on a plain line, no `SourceItem` is constructed.
+    pub fn do_match_line(&self, line: &str, query: &str) -> MatchResult {
+        let (text, offset) = line.match_text_for(&self.match_type)?;
+        self.match_text(text, query).map(|(score, indices)| {
+            self.add_bonuses(line, score, line.origin_indices(indices, offset))
         })
     }
+
+    fn add_bonuses(&self, raw: &str, score: Score, indices: Vec<usize>) -> (Score, Vec<usize>) {
+        let bonus_score = self
+            .bonuses
+            .iter()
+            .map(|bonus| bonus.calculate(raw, score, &indices))
+            .sum::<Score>();
+        (score + bonus_score, indices)
+    }
 }
 
 #[cfg(test)]
@@ -130,7 +275,12 @@ mod tests {
     #[test]
     fn test_exclude_grep_filepath() {
         fn apply_on_grep_line_fzy(item: &SourceItem, query: &str) -> MatchResult {
-            Algo::Fzy.apply_match(query, item, &MatchType::IgnoreFilePath)
+            Algo::Fzy.apply_match(
+                query,
+                item,
+                &MatchType::IgnoreFilePath,
+                &CaseMatching::Smart,
+            )
         }
 
         let query = "rules";
@@ -143,7 +293,7 @@ mod tests {
     #[test]
     fn test_file_name_only() {
         fn apply_on_file_line_fzy(item: &SourceItem, query: &str) -> MatchResult {
-            Algo::Fzy.apply_match(query, item, &MatchType::FileName)
+            Algo::Fzy.apply_match(query, item, &MatchType::FileName, &CaseMatching::Smart)
         }
 
         let query = "lib";
@@ -153,6 +303,194 @@ mod tests {
         assert_eq!(origin_indices, indices);
     }
 
+    #[test]
+    fn test_unicode_char_indices() {
+        fn matched_chars(line: &str, indices: &[usize]) -> String {
+            indices
+                .iter()
+                .map(|idx| line.chars().nth(*idx).unwrap())
+                .collect()
+        }
+
+        let cases = vec![
+            // CJK
+            ("crates/漢語/src/汉语.rs", "汉语", MatchType::Full),
+            (
+                "src/汉语.rs:1:1:fn 你好() {}",
+                "你好",
+                MatchType::IgnoreFilePath,
+            ),
+            ("crates/漢語/src/汉语.rs", "汉语", MatchType::FileName),
+            // Emoji
+            ("🦀🦀 crab/src/main.rs", "main", MatchType::Full),
+            (
+                "🦀/🦀.rs:10:2:let 🦀 = crab;",
+                "crab",
+                MatchType::IgnoreFilePath,
+            ),
+            // Combining characters
+            ("cafe\u{301}/me\u{301}nu.rs", "nu", MatchType::Full),
+            ("cafe\u{301}/me\u{301}nu.rs", "nu", MatchType::FileName),
+        ];
+
+        for algo in &[Algo::Fzy, Algo::Skim, Algo::SubString] {
+            for (line, query, match_type) in cases.iter() {
+                let (_, indices) = algo
+                    .apply_match(
+                        query,
+                        &SourceItem::from(*line),
+                        match_type,
+                        &CaseMatching::Smart,
+                    )
+                    .unwrap();
+                assert_eq!(
+                    matched_chars(line, &indices),
+                    *query,
+                    "algo: {:?}, line: {}",
+                    algo,
+                    line
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_case_matching() {
+        let item: SourceItem = "crates/Matcher/src/lib.rs".into();
+        for algo in &[Algo::Fzy, Algo::Skim, Algo::SubString] {
+            let apply = |query: &str, case_matching: CaseMatching| {
+                algo.apply_match(query, &item, &MatchType::Full, &case_matching)
+            };
+            assert!(apply("matcher", CaseMatching::Smart).is_some());
+            assert!(apply("MATCHER", CaseMatching::Smart).is_none());
+            assert!(apply("MATCHER", CaseMatching::Ignore).is_some());
+            assert!(apply("matcher", CaseMatching::Respect).is_none());
+            assert!(apply("Matcher", CaseMatching::Respect).is_some());
+        }
+    }
+
+    #[test]
+    fn test_custom_scorer() {
+        // Matches the query as the prefix of text.
+        let prefix_scorer = |query: &str, text: &str, _: &CaseMatching| {
+            if text.starts_with(query) {
+                Some((query.len() as Score, (0..query.chars().count()).collect()))
+            } else {
+                None
+            }
+        };
+
+        let matcher = Matcher::new(
+            Algo::Fzy,
+            MatchType::FileName,
+            Bonus::None,
+            CaseMatching::Smart,
+        )
+        .with_scorer(prefix_scorer);
+
+        assert_eq!(
+            matcher.do_match(&"crates/matcher/src/lib.rs".into(), "li"),
+            Some((2, vec![19, 20]))
+        );
+        assert!(matcher
+            .do_match(&"crates/matcher/src/lib.rs".into(), "ib")
+            .is_none());
+    }
+
+    #[test]
+    fn test_typo_tag_name() {
+        let matcher = Matcher::new(
+            Algo::Typo,
+            MatchType::TagName,
+            Bonus::None,
+            CaseMatching::Smart,
+        );
+        let line = "receive:12                     [function@src/net.rs]  fn receive() {";
+        assert_eq!(
+            matcher.do_match(&line.into(), "recieve").unwrap().1,
+            (0..7).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_tiebreaks() {
+        let matcher = Matcher::new(Algo::Fzy, MatchType::Full, Bonus::None, CaseMatching::Smart);
+        let sort = |matcher: &Matcher, mut items: Vec<(SourceItem, Score, Vec<usize>)>| {
+            items.sort_by(|a, b| matcher.compare_results((&a.0, a.1, &a.2), (&b.0, b.1, &b.2)));
+            items
+                .into_iter()
+                .map(|(item, _, _)| item.raw)
+                .collect::<Vec<_>>()
+        };
+
+        let items = vec![
+            ("src/foo/lib.rs".into(), 10, vec![4]),
+            ("src/lib.rs".into(), 10, vec![6]),
+            ("lib.rs".into(), 20, vec![0]),
+            ("a/b/lib.rs".into(), 10, vec![0]),
+        ];
+
+        assert_eq!(
+            sort(&matcher, items.clone()),
+            vec!["lib.rs", "src/foo/lib.rs", "src/lib.rs", "a/b/lib.rs"]
+        );
+
+        let matcher = matcher.with_tiebreaks(vec![Tiebreak::Length, Tiebreak::Begin]);
+        assert_eq!(
+            sort(&matcher, items.clone()),
+            vec!["lib.rs", "a/b/lib.rs", "src/lib.rs", "src/foo/lib.rs"]
+        );
+
+        let matcher = matcher.with_tiebreaks(vec![Tiebreak::Begin, Tiebreak::PathDepth]);
+        assert_eq!(
+            sort(&matcher, items.clone()),
+            vec!["lib.rs", "a/b/lib.rs", "src/foo/lib.rs", "src/lib.rs"]
+        );
+
+        let matcher = matcher.with_tiebreaks(vec![Tiebreak::Index, Tiebreak::Length]);
+        assert_eq!(
+            sort(&matcher, items),
+            vec!["lib.rs", "src/foo/lib.rs", "src/lib.rs", "a/b/lib.rs"]
+        );
+    }
+
+    #[test]
+    fn test_normalized_match() {
+        let line = "docs/Ｒｅｓｕｍｅ/re\u{301}sume\u{301}-café.md";
+        let matcher = Matcher::new(Algo::Fzy, MatchType::Full, Bonus::None, CaseMatching::Smart);
+        assert!(matcher.do_match(&line.into(), "cafe").is_none());
+
+        let matcher = matcher.with_normalization(true);
+        for (query, expected) in vec![
+            ("cafe", "café"),
+            ("Resume", "Ｒｅｓｕｍｅ"),
+            ("résumé-", "resume-"),
+        ] {
+            let (_, indices) = matcher.do_match(&line.into(), query).unwrap();
+            let matched = indices
+                .iter()
+                .map(|idx| line.chars().nth(*idx).unwrap())
+                .collect::<String>();
+            assert_eq!(matched, expected);
+        }
+    }
+
+    #[test]
+    fn test_non_contiguous_fields() {
+        let spec =
+            source_item::FieldSpec::new(Some(":".into()), Some("1,3".parse().unwrap()), None);
+        let item = SourceItem::from_fields("src/lib.rs:12:fn main() {".into(), &spec);
+        let matcher = Matcher::new(Algo::Fzy, MatchType::Full, Bonus::None, CaseMatching::Smart);
+        assert!(matcher.do_match(&item, "12").is_none());
+
+        let (_, indices) = matcher.do_match(&item, "lib:fn").unwrap();
+        let matched = indices
+            .iter()
+            .map(|idx| item.display_text().chars().nth(*idx).unwrap())
+            .collect::<String>();
+        assert_eq!(matched, "lib:fn");
+    }
+
     #[test]
     fn test_filename_bonus() {
         let lines = vec![
@@ -160,7 +498,12 @@ mod tests {
             "autoload/clap/provider/files.vim",
             "lua/fzy_filter.lua",
         ];
-        let matcher = Matcher::new(Algo::Fzy, MatchType::Full, Bonus::FileName);
+        let matcher = Matcher::new(
+            Algo::Fzy,
+            MatchType::Full,
+            Bonus::FileName,
+            CaseMatching::Smart,
+        );
         let query = "fil";
         for line in lines {
             let (base_score, indices1) = matcher.base_match(&line.into(), query).unwrap();
@@ -0,0 +1,106 @@
+//! Normalizes the text before matching so that the query `cafe` can match `café` and `ｃａｆｅ`.
+//!
+//! Each char is decomposed in the compatibility form (NFKD), which turns the full-width chars
+//! to the half-width ones and the other compatibility forms to the canonical ones, then the
+//! combining marks are removed to fold the diacritics.
+
+use std::borrow::Cow;
+
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Normalized text with the mapping to the char indices of the origin text.
+#[derive(Debug, Clone)]
+pub struct NormalizedText<'a> {
+    /// Text used for matching, borrowed from the origin text if it's unchanged.
+    pub text: Cow<'a, str>,
+    /// The char index in origin text of each char in `text`.
+    ///
+    /// `None` if the text is unchanged after the normalization.
+    origin_indices: Option<Vec<usize>>,
+}
+
+impl NormalizedText<'_> {
+    /// Converts the matched indices of normalized text to the char indices of origin text.
+    ///
+    /// One origin char can be normalized to multiple chars, which are deduplicated here.
+    pub fn origin_indices(&self, indices: Vec<usize>) -> Vec<usize> {
+        match self.origin_indices {
+            Some(ref origin_indices) => {
+                let mut indices = indices
+                    .into_iter()
+                    .filter_map(|idx| origin_indices.get(idx).copied())
+                    .collect::<Vec<_>>();
+                indices.dedup();
+                indices
+            }
+            None => indices,
+        }
+    }
+}
+
+/// Normalizes `text` by folding the diacritics and the compatibility forms.
+pub fn normalize(text: &str) -> NormalizedText<'_> {
+    if text.is_ascii() {
+        return NormalizedText {
+            text: Cow::Borrowed(text),
+            origin_indices: None,
+        };
+    }
+
+    let mut normalized = String::with_capacity(text.len());
+    let mut origin_indices = Vec::with_capacity(text.len());
+
+    for (idx, ch) in text.chars().enumerate() {
+        for c in std::iter::once(ch)
+            .nfkd()
+            .filter(|c| !is_combining_mark(*c))
+        {
+            normalized.push(c);
+            origin_indices.push(idx);
+        }
+    }
+
+    NormalizedText {
+        text: Cow::Owned(normalized),
+        origin_indices: Some(origin_indices),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let cases = vec![
+            ("café", "cafe"),
+            ("cafe\u{301}", "cafe"),
+            ("ｃａｆｅ１２", "cafe12"),
+            ("Ångström", "Angstrom"),
+            ("ﬁle", "file"),
+            ("汉语", "汉语"),
+            ("cafe", "cafe"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(normalize(text).text, expected);
+        }
+    }
+
+    #[test]
+    fn test_ascii_is_borrowed() {
+        let normalized = normalize("src/lib.rs");
+        assert!(matches!(normalized.text, Cow::Borrowed("src/lib.rs")));
+        assert_eq!(normalized.origin_indices(vec![0, 4]), vec![0, 4]);
+    }
+
+    #[test]
+    fn test_origin_indices() {
+        // `ﬁ` is normalized to `fi`.
+        let normalized = normalize("ﬁlé\u{301}s");
+        assert_eq!(normalized.text, "files");
+        assert_eq!(
+            normalized.origin_indices(vec![0, 1, 2, 3, 4]),
+            vec![0, 1, 2, 4]
+        );
+    }
+}
@@ -1,11 +1,15 @@
 //! This crate provides the feature of diplaying the information of filtered lines
 //! by printing them to stdout in JSON format.
 
-use icon::{IconPainter, ICON_LEN};
+mod output;
+
+use icon::{IconPainter, ICON_CHAR_LEN};
 use source_item::SourceItem;
 use std::collections::HashMap;
 use utility::{println_json, println_json_with_length};
 
+pub use output::{Output, OutputFormat, OutputItem, OUTPUT_VERSION};
+
 pub const DOTS: &str = "..";
 
 /// Line number of Vim is 1-based.
@@ -33,6 +37,8 @@ fn utf8_str_slice(line: &str, start: usize, end: usize) -> String {
 
 /// Long matched lines can cause the matched items invisible.
 ///
+/// The truncation is char-based as the matched indices are char indices.
+///
 /// # Arguments
 ///
 /// - winwidth: width of the display window.
@@ -70,7 +76,7 @@ pub fn truncate_long_matched_lines<T>(
                     if start >= indices[0] || (indices.len() > 1 && *last_idx - start > winwidth) {
                         start = indices[0];
                     }
-                    let line_len = line.len();
+                    let line_len = line.chars().count();
                     // [--------------------------]
                     // [-----------------------------------------------------------------xx--x--]
                     for _ in 0..3 {
@@ -84,7 +90,7 @@ pub fn truncate_long_matched_lines<T>(
                     if trailing_dist < indices[0] - start {
                         start += trailing_dist;
                     }
-                    let end = line.len();
+                    let end = line_len;
                     let truncated = if let Some(n) = skipped {
                         let icon: String = line.chars().take(n).collect();
                         start += n;
@@ -92,7 +98,7 @@ pub fn truncate_long_matched_lines<T>(
                     } else {
                         format!("{}{}", DOTS, utf8_str_slice(&line, start, end))
                     };
-                    let offset = line_len - truncated.len();
+                    let offset = line_len - truncated.chars().count();
                     let truncated_indices = indices.iter().map(|x| x - offset).collect::<Vec<_>>();
                     truncated_map.insert(lnum, line);
                     (truncated, score, truncated_indices)
@@ -114,19 +120,30 @@ pub fn process_top_items<T>(
     winwidth: Option<usize>,
     icon_painter: Option<IconPainter>,
 ) -> (Vec<String>, Vec<Vec<usize>>, LinesTruncatedMap) {
+    let top_list = top_list.into_iter().collect::<Vec<_>>();
+    // Prefer the typed metadata filled by the source producer to parsing the line.
+    let metadata_icons = icon_painter.as_ref().map(|painter| {
+        top_list
+            .iter()
+            .map(|(item, _, _)| {
+                item.metadata.as_ref().and_then(|metadata| {
+                    painter.get_icon_by_metadata(metadata.path.as_deref(), metadata.kind.as_deref())
+                })
+            })
+            .collect::<Vec<_>>()
+    });
     let (truncated_lines, truncated_map) =
         truncate_long_matched_lines(top_list, winwidth.unwrap_or(62), None);
     let mut lines = Vec::with_capacity(top_size);
     let mut indices = Vec::with_capacity(top_size);
-    if let Some(painter) = icon_painter {
+    if let (Some(painter), Some(metadata_icons)) = (icon_painter, metadata_icons) {
         for (idx, (text, _, idxs)) in truncated_lines.iter().enumerate() {
-            let iconized = if let Some(origin_text) = truncated_map.get(&(idx + 1)) {
-                format!("{} {}", painter.get_icon(origin_text), text)
-            } else {
-                painter.paint(&text)
-            };
-            lines.push(iconized);
-            indices.push(idxs.iter().map(|x| x + ICON_LEN).collect());
+            let icon = metadata_icons[idx].unwrap_or_else(|| match truncated_map.get(&(idx + 1)) {
+                Some(origin_text) => painter.get_icon(origin_text),
+                None => painter.get_icon(text),
+            });
+            lines.push(format!("{} {}", icon, text));
+            indices.push(idxs.iter().map(|x| x + ICON_CHAR_LEN).collect());
         }
     } else {
         for (text, _, idxs) in truncated_lines {
@@ -191,7 +208,7 @@ pub fn print_dyn_filter_results(
 mod tests {
     use super::*;
     use filter::{
-        matcher::{Algo, Bonus, MatchType, Matcher},
+        matcher::{Algo, Bonus, CaseMatching, MatchType, Matcher},
         Source,
     };
     use rayon::prelude::*;
@@ -230,8 +247,13 @@ mod tests {
         skipped: Option<usize>,
         winwidth: usize,
     ) {
-        let matcher = Matcher::new(Algo::Fzy, MatchType::Full, Bonus::FileName);
-        let mut ranked = source.filter(matcher, query).unwrap();
+        let matcher = Matcher::new(
+            Algo::Fzy,
+            MatchType::Full,
+            Bonus::FileName,
+            CaseMatching::Smart,
+        );
+        let mut ranked = source.filter(&matcher, query).unwrap();
         ranked.par_sort_unstable_by(|(_, v1, _), (_, v2, _)| v2.partial_cmp(&v1).unwrap());
 
         println!();
@@ -319,6 +341,26 @@ mod tests {
         run_test(source, query, None, 50usize);
     }
 
+    #[test]
+    fn test_truncate_multibyte_line() {
+        let line = "数据/目录/嵌套/很深/然后/匹配的/条目/将会/不可见/的/文件/汉语.rs";
+        let query = "汉语";
+        let matcher = Matcher::new(Algo::Fzy, MatchType::Full, Bonus::None, CaseMatching::Smart);
+        let (score, indices) = matcher.do_match(&line.into(), query).unwrap();
+        let (truncated_lines, truncated_map) =
+            truncate_long_matched_lines(vec![(line.into(), score, indices)], 20, None);
+        let (truncated_line, _, truncated_indices) = &truncated_lines[0];
+        assert!(truncated_line.starts_with(DOTS));
+        assert_eq!(truncated_map.get(&1).unwrap(), line);
+        assert_eq!(
+            truncated_indices
+                .iter()
+                .map(|idx| truncated_line.chars().nth(*idx).unwrap())
+                .collect::<String>(),
+            query
+        );
+    }
+
     #[test]
     fn test_print_multibyte_string_slice() {
         let multibyte_str = "README.md:23:1:Gourinath Banda. “Scalable Real-Time Kernel for Small Embedded Systems”. En- glish. PhD thesis. Denmark: University of Southern Denmark, June 2003. URL: http://citeseerx.ist.psu.edu/viewdoc/download;jsessionid=84D11348847CDC13691DFAED09883FCB?doi=10.1.1.118.1909&rep=rep1&type=pdf.";
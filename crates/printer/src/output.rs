@@ -0,0 +1,248 @@
+//! Versioned output of maple for the scripts and non-Vim clients, enabled by `--format`.
+//!
+//! Schema version 1:
+//!
+//! - `json`: one JSON object per invocation, e.g.,
+//!   `{"version": 1, "total": 2, "items": [{"text": "src/lib.rs", "indices": [0, 4]}]}`,
+//!   `tempfile` is present if the whole output has been written to a cache file, `using_cache`
+//!   is present and true if that cache file is reused, `stale` is present and true if the reused
+//!   cache file is outdated and being regenerated in the background, `warning` is present if
+//!   the command failed by itself but its output is still usable, e.g., `rg` exits with 2 on an
+//!   unreadable file.
+//! - `jsonl`: one JSON object per item, e.g.,
+//!   `{"version": 1, "text": "src/lib.rs", "indices": [0, 4]}`.
+//! - `plain`: the text of each item terminated by `\n`.
+//! - `null-separated`: the text of each item terminated by `\0`.
+//!
+//! `total` is the number of all the matched items, `items` are the top ones limited by
+//! `--number`, or only the first ones if the whole output has been written to `tempfile`.
+//! `indices` are the char indices of the matched elements in `text`, empty if there is no query.
+//! `text` is the displayed text of the item, neither truncated nor prepended with an icon. The
+//! plain formats write the original bytes of the items containing invalid UTF-8.
+
+use crate::FilterResult;
+use serde::Serialize;
+use source_item::SourceItem;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use utility::CommandError;
+
+/// Version of the output schema, bumped on the incompatible changes.
+pub const OUTPUT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Json,
+    Jsonl,
+    Plain,
+    NullSeparated,
+}
+
+impl OutputFormat {
+    pub fn variants() -> [&'static str; 4] {
+        ["json", "jsonl", "plain", "null-separated"]
+    }
+
+    /// Returns true if the items are written one by one, so that each item can be written as
+    /// soon as it's available.
+    pub fn is_per_item(self) -> bool {
+        self != Self::Json
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::Jsonl),
+            "plain" => Ok(Self::Plain),
+            "null-separated" => Ok(Self::NullSeparated),
+            _ => Err(format!("Invalid output format: {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputItem {
+    pub text: String,
+    pub indices: Vec<usize>,
+    /// The original bytes of `text` if it's not valid UTF-8.
+    #[serde(skip)]
+    pub raw_bytes: Option<Vec<u8>>,
+}
+
+impl OutputItem {
+    fn bytes(&self) -> &[u8] {
+        self.raw_bytes.as_deref().unwrap_or(self.text.as_bytes())
+    }
+
+    /// Writes the item alone in the per-item `format`, see [`OutputFormat::is_per_item`].
+    pub fn write_to<W: Write>(&self, mut writer: W, format: OutputFormat) -> io::Result<()> {
+        match format {
+            OutputFormat::Json => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "json output can not be written per item",
+                ))
+            }
+            OutputFormat::Jsonl => {
+                let item = JsonlItem {
+                    version: OUTPUT_VERSION,
+                    item: self,
+                };
+                serde_json::to_writer(&mut writer, &item)?;
+                writer.write_all(b"\n")?;
+            }
+            OutputFormat::Plain => {
+                writer.write_all(self.bytes())?;
+                writer.write_all(b"\n")?;
+            }
+            OutputFormat::NullSeparated => {
+                writer.write_all(self.bytes())?;
+                writer.write_all(b"\0")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<SourceItem> for OutputItem {
+    fn from(item: SourceItem) -> Self {
+        (item, 0, Vec::new()).into()
+    }
+}
+
+impl From<FilterResult> for OutputItem {
+    fn from((item, _, indices): FilterResult) -> Self {
+        match item.display_text {
+            Some(text) => Self {
+                text,
+                indices,
+                raw_bytes: None,
+            },
+            None => Self {
+                text: item.raw,
+                indices,
+                raw_bytes: item.raw_bytes,
+            },
+        }
+    }
+}
+
+impl From<Vec<u8>> for OutputItem {
+    fn from(line: Vec<u8>) -> Self {
+        SourceItem::from(line).into()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Output {
+    pub version: u32,
+    pub total: usize,
+    pub items: Vec<OutputItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tempfile: Option<PathBuf>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub using_cache: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stale: bool,
+    /// The error of command whose output is still usable.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<CommandError>,
+}
+
+#[derive(Serialize)]
+struct JsonlItem<'a> {
+    version: u32,
+    #[serde(flatten)]
+    item: &'a OutputItem,
+}
+
+impl Output {
+    pub fn new(total: usize, items: impl IntoIterator<Item = impl Into<OutputItem>>) -> Self {
+        Self {
+            version: OUTPUT_VERSION,
+            total,
+            items: items.into_iter().map(Into::into).collect(),
+            tempfile: None,
+            using_cache: false,
+            stale: false,
+            warning: None,
+        }
+    }
+
+    /// Constructs the output of the ranked filtered results, only the top `number` items are
+    /// kept if specified.
+    pub fn from_filter_results(ranked: Vec<FilterResult>, number: Option<usize>) -> Self {
+        let total = ranked.len();
+        Self::new(total, ranked.into_iter().take(number.unwrap_or(total)))
+    }
+
+    /// Writes the output in the given `format`.
+    pub fn write_to<W: Write>(&self, mut writer: W, format: OutputFormat) -> io::Result<()> {
+        match format {
+            OutputFormat::Json => {
+                serde_json::to_writer(&mut writer, self)?;
+                writer.write_all(b"\n")?;
+            }
+            format => {
+                for item in &self.items {
+                    item.write_to(&mut writer, format)?;
+                }
+            }
+        }
+        writer.flush()
+    }
+
+    /// Prints the output to stdout in the given `format`.
+    pub fn print(&self, format: OutputFormat) -> io::Result<()> {
+        let stdout = io::stdout();
+        self.write_to(io::BufWriter::new(stdout.lock()), format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_formats() {
+        let ranked = vec![
+            (SourceItem::from("src/lib.rs"), 10, vec![0, 4]),
+            (SourceItem::from(b"caf\xe9.rs".to_vec()), 5, vec![0]),
+            (SourceItem::from("README.md"), 1, vec![]),
+        ];
+        let output = Output::from_filter_results(ranked, Some(2));
+
+        let write = |format| {
+            let mut buf = Vec::new();
+            output.write_to(&mut buf, format).unwrap();
+            buf
+        };
+
+        assert_eq!(
+            String::from_utf8(write(OutputFormat::Json)).unwrap(),
+            "{\"version\":1,\"total\":3,\"items\":[{\"text\":\"src/lib.rs\",\"indices\":[0,4]},\
+             {\"text\":\"caf\u{FFFD}.rs\",\"indices\":[0]}]}\n"
+        );
+        assert_eq!(
+            String::from_utf8(write(OutputFormat::Jsonl)).unwrap(),
+            "{\"version\":1,\"text\":\"src/lib.rs\",\"indices\":[0,4]}\n\
+             {\"version\":1,\"text\":\"caf\u{FFFD}.rs\",\"indices\":[0]}\n"
+        );
+        assert_eq!(write(OutputFormat::Plain), b"src/lib.rs\ncaf\xe9.rs\n");
+        assert_eq!(
+            write(OutputFormat::NullSeparated),
+            b"src/lib.rs\0caf\xe9.rs\0"
+        );
+
+        assert_eq!(
+            "Null-Separated".parse::<OutputFormat>(),
+            Ok(OutputFormat::NullSeparated)
+        );
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+}
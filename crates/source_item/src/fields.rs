@@ -0,0 +1,348 @@
+//! Field-delimited matching and displaying, similar to fzf's `--nth` and `--with-nth`.
+//!
+//! A line is split into fields either AWK-style (runs of whitespace) or by a literal delimiter,
+//! every field keeps its trailing delimiter:
+//!
+//! //   2021-01-01 8ed4391 Rename sign
+//! //   |---------||------||-----||---|
+//! //       1         2       3    4
+//! //      -4        -3      -2   -1
+
+use std::str::FromStr;
+
+/// Field index expression, the index is 1-based and negative index counts from the last field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldRange {
+    /// `N`
+    Single(i64),
+    /// `N..`
+    From(i64),
+    /// `..N`
+    To(i64),
+    /// `N..M`
+    Between(i64, i64),
+    /// `..`
+    All,
+}
+
+impl FieldRange {
+    /// Returns the inclusive 0-based range of fields given the total number of fields.
+    fn resolve(&self, total: usize) -> Option<(usize, usize)> {
+        let to_idx = |n: i64| -> Option<usize> {
+            if n > 0 {
+                Some(n as usize - 1)
+            } else if n < 0 && (n.unsigned_abs() as usize) <= total {
+                Some(total - n.unsigned_abs() as usize)
+            } else {
+                None
+            }
+        };
+
+        let last = total.checked_sub(1)?;
+
+        let (start, end) = match *self {
+            Self::Single(n) => {
+                let idx = to_idx(n)?;
+                (idx, idx)
+            }
+            Self::From(n) => (to_idx(n).unwrap_or(0), last),
+            Self::To(n) => (0, to_idx(n)?),
+            Self::Between(n, m) => (to_idx(n).unwrap_or(0), to_idx(m)?),
+            Self::All => (0, last),
+        };
+
+        if start > end || start > last {
+            None
+        } else {
+            Some((start, end.min(last)))
+        }
+    }
+}
+
+impl FromStr for FieldRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_index = |n: &str| -> Result<i64, String> {
+            match n.trim().parse::<i64>() {
+                Ok(0) | Err(_) => Err(format!("Invalid field index: {:?}", n)),
+                Ok(idx) => Ok(idx),
+            }
+        };
+
+        let s = s.trim();
+        if let Some(pos) = s.find("..") {
+            let (start, end) = (&s[..pos], &s[pos + 2..]);
+            match (start.is_empty(), end.is_empty()) {
+                (true, true) => Ok(Self::All),
+                (true, false) => Ok(Self::To(parse_index(end)?)),
+                (false, true) => Ok(Self::From(parse_index(start)?)),
+                (false, false) => Ok(Self::Between(parse_index(start)?, parse_index(end)?)),
+            }
+        } else {
+            Ok(Self::Single(parse_index(s)?))
+        }
+    }
+}
+
+/// A comma-separated list of field index expressions, e.g., `1,3..`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldRanges(Vec<FieldRange>);
+
+impl FromStr for FieldRanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ranges = s
+            .split(',')
+            .filter(|x| !x.trim().is_empty())
+            .map(FieldRange::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        if ranges.is_empty() {
+            Err(format!("Empty field index expression: {:?}", s))
+        } else {
+            Ok(Self(ranges))
+        }
+    }
+}
+
+impl FieldRanges {
+    /// Returns the indices of selected fields in the order of field index expressions.
+    fn selected(&self, total: usize) -> Vec<usize> {
+        self.0
+            .iter()
+            .filter_map(|range| range.resolve(total))
+            .flat_map(|(start, end)| start..=end)
+            .collect()
+    }
+}
+
+/// Returns the byte ranges of fields in `line`, each field includes its trailing delimiter.
+pub fn split_fields(line: &str, delimiter: Option<&str>) -> Vec<(usize, usize)> {
+    let mut fields = Vec::new();
+
+    match delimiter {
+        Some(delimiter) if !delimiter.is_empty() => {
+            let mut start = 0;
+            for (idx, _) in line.match_indices(delimiter) {
+                fields.push((start, idx + delimiter.len()));
+                start = idx + delimiter.len();
+            }
+            if start < line.len() {
+                fields.push((start, line.len()));
+            }
+        }
+        _ => {
+            let mut start = 0;
+            let mut in_whitespace = false;
+            for (idx, ch) in line.char_indices() {
+                if ch.is_whitespace() {
+                    in_whitespace = true;
+                } else if in_whitespace {
+                    // The leading whitespaces belong to the first field.
+                    if line[start..idx].trim().is_empty() {
+                        in_whitespace = false;
+                        continue;
+                    }
+                    fields.push((start, idx));
+                    start = idx;
+                    in_whitespace = false;
+                }
+            }
+            if start < line.len() {
+                fields.push((start, line.len()));
+            }
+        }
+    }
+
+    fields
+}
+
+/// Options for extracting the fields used for matching and displaying from the raw line.
+#[derive(Debug, Clone, Default)]
+pub struct FieldSpec {
+    /// Literal field delimiter, AWK-style whitespace splitting is used if not specified.
+    pub delimiter: Option<String>,
+    /// Fields of the display text used for matching.
+    pub nth: Option<FieldRanges>,
+    /// Fields of the raw line used for displaying.
+    pub with_nth: Option<FieldRanges>,
+}
+
+impl FieldSpec {
+    pub fn new(
+        delimiter: Option<String>,
+        nth: Option<FieldRanges>,
+        with_nth: Option<FieldRanges>,
+    ) -> Self {
+        Self {
+            delimiter,
+            nth,
+            with_nth,
+        }
+    }
+
+    /// Returns true if there is no need to split the line at all.
+    pub fn is_empty(&self) -> bool {
+        self.nth.is_none() && self.with_nth.is_none()
+    }
+
+    fn trim_delimiter<'a>(&self, text: &'a str) -> &'a str {
+        match self.delimiter.as_deref() {
+            Some(delimiter) if !delimiter.is_empty() => {
+                text.strip_suffix(delimiter).unwrap_or(text)
+            }
+            _ => text.trim_end(),
+        }
+    }
+
+    /// Returns the text of `--with-nth` fields for displaying.
+    pub fn display_text(&self, line: &str) -> Option<String> {
+        let with_nth = self.with_nth.as_ref()?;
+        let fields = split_fields(line, self.delimiter.as_deref());
+        let text = with_nth
+            .selected(fields.len())
+            .into_iter()
+            .map(|idx| &line[fields[idx].0..fields[idx].1])
+            .collect::<String>();
+        Some(self.trim_delimiter(&text).into())
+    }
+
+    /// Returns the text of `--nth` fields and the char offsets of its pieces in `line`.
+    ///
+    /// The selected fields are joined in the order of field index expressions, the adjacent ones
+    /// make up a single piece.
+    pub fn match_text(&self, line: &str) -> Option<(String, MatchPieces)> {
+        let nth = self.nth.as_ref()?;
+        let fields = split_fields(line, self.delimiter.as_deref());
+
+        let mut text = String::new();
+        let mut pieces = Vec::new();
+        let mut last_end = None;
+        for idx in nth.selected(fields.len()) {
+            let (start, end) = fields[idx];
+            if last_end != Some(start) {
+                pieces.push((text.chars().count(), line[..start].chars().count()));
+            }
+            text.push_str(&line[start..end]);
+            last_end = Some(end);
+        }
+
+        Some((self.trim_delimiter(&text).into(), MatchPieces(pieces)))
+    }
+}
+
+/// Char offsets of the pieces of the matching text joined from `--nth` fields, each entry is
+/// `(char_index_in_matching_text, char_offset_in_line)` of a piece.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MatchPieces(Vec<(usize, usize)>);
+
+impl MatchPieces {
+    /// Returns true if the matching text is a contiguous part of the line.
+    pub fn is_contiguous(&self) -> bool {
+        self.0.len() <= 1
+    }
+
+    /// Returns the char offset of the first piece in the line.
+    pub fn offset(&self) -> usize {
+        self.0.first().map(|&(_, offset)| offset).unwrap_or(0)
+    }
+
+    /// Converts the char index in the matching text to the char index in the line.
+    pub fn origin_index(&self, idx: usize) -> usize {
+        match self.0.iter().rev().find(|&&(start, _)| start <= idx) {
+            Some(&(start, offset)) => offset + idx - start,
+            None => idx,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields_of<'a>(line: &'a str, delimiter: Option<&str>) -> Vec<&'a str> {
+        split_fields(line, delimiter)
+            .into_iter()
+            .map(|(start, end)| &line[start..end])
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_field_ranges() {
+        assert_eq!(
+            "1,-1,2..,..3,2..-2,..".parse::<FieldRanges>().unwrap(),
+            FieldRanges(vec![
+                FieldRange::Single(1),
+                FieldRange::Single(-1),
+                FieldRange::From(2),
+                FieldRange::To(3),
+                FieldRange::Between(2, -2),
+                FieldRange::All,
+            ])
+        );
+        assert!("0".parse::<FieldRanges>().is_err());
+        assert!("a..b".parse::<FieldRanges>().is_err());
+        assert!("".parse::<FieldRanges>().is_err());
+    }
+
+    #[test]
+    fn test_split_fields() {
+        assert_eq!(
+            fields_of("  2021-01-01 8ed4391   Rename sign", None),
+            vec!["  2021-01-01 ", "8ed4391   ", "Rename ", "sign"]
+        );
+        assert_eq!(fields_of("a:b::c", Some(":")), vec!["a:", "b:", ":", "c"]);
+    }
+
+    #[test]
+    fn test_field_spec() {
+        let line = "8ed4391\t2019-10-18\tRename sign and rooter related options";
+        let spec = FieldSpec::new(
+            Some("\t".into()),
+            Some("-1".parse().unwrap()),
+            Some("1,3".parse().unwrap()),
+        );
+        let display = spec.display_text(line).unwrap();
+        assert_eq!(display, "8ed4391\tRename sign and rooter related options");
+        assert_eq!(
+            spec.match_text(&display).unwrap(),
+            (
+                "Rename sign and rooter related options".into(),
+                MatchPieces(vec![(0, 8)])
+            )
+        );
+
+        let spec = FieldSpec::new(None, Some("2..3".parse().unwrap()), None);
+        assert_eq!(
+            spec.match_text("a  bb ccc dddd").unwrap(),
+            ("bb ccc".into(), MatchPieces(vec![(0, 3)]))
+        );
+        assert_eq!(
+            spec.match_text("a").unwrap(),
+            (String::new(), MatchPieces(vec![]))
+        );
+        assert_eq!(FieldRange::Single(i64::MIN).resolve(3), None);
+
+        let item = crate::SourceItem::from_field_bytes(b"a  caf\xe9 ccc".to_vec(), &spec);
+        assert_eq!(item.match_text(), "caf\u{FFFD} ccc");
+        assert_eq!(item.raw_bytes(), b"a  caf\xe9 ccc");
+    }
+
+    #[test]
+    fn test_non_contiguous_nth() {
+        let spec = FieldSpec::new(Some(":".into()), Some("1,3".parse().unwrap()), None);
+        let line = "src/lib.rs:12:fn main() {";
+        let (text, pieces) = spec.match_text(line).unwrap();
+        assert_eq!(text, "src/lib.rs:fn main() {");
+        assert!(!pieces.is_contiguous());
+        assert_eq!(pieces.origin_index(0), 0);
+        assert_eq!(pieces.origin_index(10), 10);
+        assert_eq!(pieces.origin_index(11), 14);
+
+        let item = crate::SourceItem::from_fields(line.into(), &spec);
+        assert_eq!(item.match_text(), "src/lib.rs:fn main() {");
+        assert!(!item.match_text().contains("12"));
+        assert_eq!(item.origin_indices(vec![0, 11, 12], 0), vec![0, 14, 15]);
+    }
+}
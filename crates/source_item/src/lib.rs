@@ -1,8 +1,19 @@
+mod fields;
+mod metadata;
+
+use std::borrow::Cow;
+
 use structopt::clap::arg_enum;
 
 use pattern::{file_name_only, strip_grep_filepath, tag_name_only};
 
+pub use fields::{split_fields, FieldRange, FieldRanges, FieldSpec, MatchPieces};
+pub use metadata::ItemMetadata;
+
 /// A tuple of match text piece (matching_text, offset_of_matching_text).
+///
+/// The offset is the number of chars before `matching_text` in the whole line, in accordance with
+/// the matched indices which are char indices.
 pub type MatchText<'a> = (&'a str, usize);
 
 arg_enum! {
@@ -36,19 +47,57 @@ impl From<&str> for MatchType {
 /// Extracts the text for running the matcher.
 pub trait MatchTextFor<'a> {
     fn match_text_for(&self, match_ty: &MatchType) -> Option<MatchText>;
+
+    /// Converts the char indices in the match text to the char indices in the whole line.
+    fn origin_indices(&self, indices: Vec<usize>, offset: usize) -> Vec<usize> {
+        indices.into_iter().map(|x| x + offset).collect()
+    }
 }
 
 impl<'a> MatchTextFor<'a> for SourceItem {
     fn match_text_for(&self, match_type: &MatchType) -> Option<MatchText> {
         self.match_text_for(match_type)
     }
+
+    fn origin_indices(&self, indices: Vec<usize>, offset: usize) -> Vec<usize> {
+        self.origin_indices(indices, offset)
+    }
+}
+
+impl<'a> MatchTextFor<'a> for str {
+    fn match_text_for(&self, match_type: &MatchType) -> Option<MatchText> {
+        line_match_text_for(self, match_type)
+    }
+}
+
+/// Extracts the text for running the matcher from a plain line.
+fn line_match_text_for<'a>(line: &'a str, match_ty: &MatchType) -> Option<MatchText<'a>> {
+    match match_ty {
+        MatchType::Full => Some((line, 0)),
+        MatchType::TagName => tag_name_only(line).map(|s| (s, 0)),
+        MatchType::FileName => file_name_only(line).map(|x| char_offset(x, line)),
+        MatchType::IgnoreFilePath => strip_grep_filepath(line).map(|x| char_offset(x, line)),
+    }
+}
+
+/// Converts the byte offset of `line` to the char offset.
+#[inline]
+fn char_offset<'a>((text, byte_offset): MatchText<'a>, line: &str) -> MatchText<'a> {
+    (text, line[..byte_offset].chars().count())
 }
 
 #[derive(Debug, Clone)]
 pub struct SourceItem {
     pub raw: String,
+    /// A tuple of (matching_text, char_offset_of_matching_text).
     pub match_text: Option<(String, usize)>,
+    /// Char offsets of the pieces of `match_text` if it's joined from the non-contiguous fields.
+    pub match_pieces: Option<MatchPieces>,
     pub display_text: Option<String>,
+    /// The original bytes of the line if it's not valid UTF-8, `raw` is decoded lossily then.
+    pub raw_bytes: Option<Vec<u8>>,
+    /// Typed metadata filled by the source producer, `None` for the opaque lines.
+    pub metadata: Option<Box<ItemMetadata>>,
 }
 
 impl From<&str> for SourceItem {
@@ -57,6 +106,9 @@ impl From<&str> for SourceItem {
             raw: s.into(),
             display_text: None,
             match_text: None,
+            match_pieces: None,
+            raw_bytes: None,
+            metadata: None,
         }
     }
 }
@@ -67,6 +119,25 @@ impl From<String> for SourceItem {
     }
 }
 
+impl From<Vec<u8>> for SourceItem {
+    fn from(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(raw) => raw.into(),
+            Err(e) => {
+                let bytes = e.into_bytes();
+                Self {
+                    raw: String::from_utf8_lossy(&bytes).into_owned(),
+                    display_text: None,
+                    match_text: None,
+                    match_pieces: None,
+                    raw_bytes: Some(bytes),
+                    metadata: None,
+                }
+            }
+        }
+    }
+}
+
 impl SourceItem {
     /// Constructs `SourceItem`.
     pub fn new(
@@ -78,9 +149,70 @@ impl SourceItem {
             raw,
             display_text,
             match_text,
+            match_pieces: None,
+            raw_bytes: None,
+            metadata: None,
         }
     }
 
+    /// Constructs `SourceItem` with the `match_text` and `display_text` extracted from the fields
+    /// of `raw` line.
+    ///
+    /// The offset of `match_text` is relative to the `display_text` as the matched indices are
+    /// used for highlighting the displayed line.
+    pub fn from_fields(raw: String, spec: &FieldSpec) -> Self {
+        let display_text = spec.display_text(&raw);
+        let (match_text, match_pieces) = match spec
+            .match_text(display_text.as_deref().unwrap_or(&raw))
+        {
+            Some((text, pieces)) if pieces.is_contiguous() => (Some((text, pieces.offset())), None),
+            Some((text, pieces)) => (Some((text, pieces.offset())), Some(pieces)),
+            None => (display_text.clone().map(|text| (text, 0)), None),
+        };
+        Self {
+            raw,
+            display_text,
+            match_text,
+            match_pieces,
+            raw_bytes: None,
+            metadata: None,
+        }
+    }
+
+    /// Constructs `SourceItem` from the fields of a line like [`Self::from_fields`], keeping the
+    /// original bytes if the line is not valid UTF-8.
+    pub fn from_field_bytes(bytes: Vec<u8>, spec: &FieldSpec) -> Self {
+        let Self { raw, raw_bytes, .. } = bytes.into();
+        Self {
+            raw_bytes,
+            ..Self::from_fields(raw, spec)
+        }
+    }
+
+    /// Attaches the typed metadata filled by the source producer.
+    pub fn with_metadata(mut self, metadata: ItemMetadata) -> Self {
+        self.metadata = Some(Box::new(metadata));
+        self
+    }
+
+    /// Returns the metadata filled by the source producer, or parses the raw line with `parse`
+    /// if it's an opaque line.
+    pub fn metadata_or_parse(
+        &self,
+        parse: impl FnOnce(&str) -> Option<ItemMetadata>,
+    ) -> Option<Cow<'_, ItemMetadata>> {
+        match self.metadata {
+            Some(ref metadata) => Some(Cow::Borrowed(metadata)),
+            None => parse(&self.raw).map(Cow::Owned),
+        }
+    }
+
+    /// Returns the exact bytes of the original line, which differ from `raw` if the line is not
+    /// valid UTF-8.
+    pub fn raw_bytes(&self) -> &[u8] {
+        self.raw_bytes.as_deref().unwrap_or(self.raw.as_bytes())
+    }
+
     pub fn display_text(&self) -> &str {
         if let Some(ref text) = self.display_text {
             text
@@ -101,11 +233,18 @@ impl SourceItem {
         if let Some((ref text, offset)) = self.match_text {
             return Some((text, offset));
         }
-        match match_ty {
-            MatchType::Full => Some((self.raw.as_str(), 0)),
-            MatchType::TagName => tag_name_only(self.raw.as_str()).map(|s| (s, 0)),
-            MatchType::FileName => file_name_only(self.raw.as_str()),
-            MatchType::IgnoreFilePath => strip_grep_filepath(self.raw.as_str()),
+        line_match_text_for(&self.raw, match_ty)
+    }
+
+    /// Converts the char indices in the match text given by [`Self::match_text_for`] to the char
+    /// indices in the displayed line.
+    pub fn origin_indices(&self, indices: Vec<usize>, offset: usize) -> Vec<usize> {
+        match self.match_pieces {
+            Some(ref pieces) => indices
+                .into_iter()
+                .map(|x| pieces.origin_index(x))
+                .collect(),
+            None => indices.into_iter().map(|x| x + offset).collect(),
         }
     }
 }
@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+
+use pattern::{
+    extract_blines_lnum, extract_buf_tags_lnum, extract_grep_position, extract_proj_tags,
+    extract_proj_tags_kind, parse_rev,
+};
+
+/// Typed metadata of the item, filled by the source producers such as grep, tags and blines so
+/// that the consumers don't have to parse the line again.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ItemMetadata {
+    /// File path of the item, relative to the working directory of the source.
+    pub path: Option<PathBuf>,
+    /// 1-based line number in `path`.
+    pub lnum: Option<usize>,
+    /// 1-based column number in `path`.
+    pub col: Option<usize>,
+    /// Kind of the tag item.
+    pub kind: Option<String>,
+    /// Git revision of the commit item.
+    pub rev: Option<String>,
+}
+
+impl ItemMetadata {
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn with_lnum(mut self, lnum: usize) -> Self {
+        self.lnum = Some(lnum);
+        self
+    }
+
+    pub fn with_col(mut self, col: usize) -> Self {
+        self.col = Some(col);
+        self
+    }
+
+    pub fn with_kind(mut self, kind: impl Into<String>) -> Self {
+        self.kind = Some(kind.into());
+        self
+    }
+
+    pub fn with_rev(mut self, rev: impl Into<String>) -> Self {
+        self.rev = Some(rev.into());
+        self
+    }
+
+    /// Parses the grep line in the form of `path:lnum:col:text`.
+    pub fn from_grep_line(line: &str) -> Option<Self> {
+        let (path, lnum, col) = extract_grep_position(line)?;
+        Some(
+            Self::default()
+                .with_path(path)
+                .with_lnum(lnum)
+                .with_col(col),
+        )
+    }
+
+    /// Parses the line of proj_tags in the form of `name:lnum [kind@path] pattern`.
+    pub fn from_proj_tags_line(line: &str) -> Option<Self> {
+        let (lnum, path) = extract_proj_tags(line)?;
+        let metadata = Self::default().with_path(path).with_lnum(lnum);
+        Some(match extract_proj_tags_kind(line) {
+            Some(kind) => metadata.with_kind(kind),
+            None => metadata,
+        })
+    }
+
+    /// Parses the line of blines in the form of `lnum text`.
+    pub fn from_blines_line(line: &str) -> Option<Self> {
+        extract_blines_lnum(line).map(|lnum| Self::default().with_lnum(lnum))
+    }
+
+    /// Parses the line of buffer tags in the form of `name:lnum [kind] pattern`.
+    pub fn from_buffer_tags_line(line: &str) -> Option<Self> {
+        extract_buf_tags_lnum(line).map(|lnum| Self::default().with_lnum(lnum))
+    }
+
+    /// Parses the line of commits containing `yyyy-mm-dd rev`.
+    pub fn from_commit_line(line: &str) -> Option<Self> {
+        parse_rev(line).map(|rev| Self::default().with_rev(rev))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SourceItem;
+
+    #[test]
+    fn test_metadata_or_parse() {
+        let line = "crates/printer/src/lib.rs:199:26:        let query = \"srlisrlisrsr\";";
+        let parsed = SourceItem::from(line)
+            .metadata_or_parse(ItemMetadata::from_grep_line)
+            .unwrap()
+            .into_owned();
+        assert_eq!(
+            parsed,
+            ItemMetadata::default()
+                .with_path("crates/printer/src/lib.rs")
+                .with_lnum(199)
+                .with_col(26)
+        );
+
+        // The typed metadata is used as is even if the line can not be parsed.
+        let item = SourceItem::from("opaque line").with_metadata(parsed.clone());
+        assert_eq!(
+            item.metadata_or_parse(ItemMetadata::from_grep_line)
+                .as_deref(),
+            Some(&parsed)
+        );
+        assert!(SourceItem::from("opaque line")
+            .metadata_or_parse(ItemMetadata::from_grep_line)
+            .is_none());
+    }
+}
@@ -1,6 +1,10 @@
 use super::*;
 use crate::types::ProviderId;
+use filter::matcher::{BonusContext, CaseMatching, Tiebreak, WeightedBonus};
+use source_item::{FieldSpec, SourceItem};
 use std::sync::{atomic::AtomicBool, Arc, Mutex};
+use std::time::Duration;
+use utility::{command_timeout, CancelToken};
 
 #[derive(Debug, Clone)]
 pub struct SessionContext {
@@ -9,8 +13,21 @@ pub struct SessionContext {
     pub winwidth: Option<u64>,
     pub provider_id: ProviderId,
     pub start_buffer_path: String,
+    pub field_spec: FieldSpec,
+    pub case_matching: CaseMatching,
+    pub normalize: bool,
+    /// Bonuses specified by the client, the default bonus of provider is used if `None`.
+    pub bonuses: Option<Vec<WeightedBonus>>,
+    pub tiebreaks: Vec<Tiebreak>,
+    /// Timeout of the forerunner command, defaults to the env var `CLAP_COMMAND_TIMEOUT`.
+    pub timeout: Option<Duration>,
+    /// Kills the forerunner command once the session is terminated.
+    pub cancel: CancelToken,
     pub is_running: Arc<Mutex<AtomicBool>>,
-    pub source_list: Arc<Mutex<Option<Vec<String>>>>,
+    /// Items of the forerunner job shared by all the OnTyped events, immutable once set.
+    pub source_list: Arc<Mutex<Option<Arc<[SourceItem]>>>>,
+    /// Indices in `source_list` of the lines displayed in order, updated by the OnTyped events.
+    pub displayed: Arc<Mutex<Vec<usize>>>,
 }
 
 impl SessionContext {
@@ -19,6 +36,41 @@ impl SessionContext {
         let out = utility::execute_at(cmd, Some(&self.cwd))?;
         Ok(out.stdout)
     }
+
+    /// Converts the raw line of source list to `SourceItem`.
+    ///
+    /// The metadata of item is parsed lazily on OnMove, only the previewed one needs it.
+    pub fn to_source_item(&self, line: Vec<u8>) -> SourceItem {
+        if self.field_spec.is_empty() {
+            SourceItem::from(line)
+        } else {
+            SourceItem::from_field_bytes(line, &self.field_spec)
+        }
+    }
+
+    /// Records the indices in source list of the lines being displayed.
+    pub fn set_displayed(&self, indices: Vec<usize>) {
+        *self.displayed.lock().unwrap() = indices;
+    }
+
+    /// Returns the item of source list displayed as `line` at the 1-based `lnum` of display
+    /// window, which carries the metadata filled by the source producer, or an opaque item of
+    /// `line` if it's not in the source list.
+    pub fn find_source_item(&self, line: String, lnum: Option<usize>) -> SourceItem {
+        let source_list = self.source_list.lock().unwrap().clone();
+        let idx = lnum.and_then(|lnum| {
+            let displayed = self.displayed.lock().unwrap();
+            displayed.get(lnum.checked_sub(1)?).copied()
+        });
+        match (source_list, idx) {
+            (Some(items), Some(idx)) => match items.get(idx) {
+                // The display may be out of date.
+                Some(item) if item.display_text() == line => item.clone(),
+                _ => line.into(),
+            },
+            _ => line.into(),
+        }
+    }
 }
 
 impl From<Message> for SessionContext {
@@ -42,14 +94,98 @@ impl From<Message> for SessionContext {
                 .expect("Missing source_fpath"),
         );
 
+        let parse_field_ranges = |key: &str| {
+            msg.params
+                .get(key)
+                .and_then(|x| x.as_str())
+                .and_then(|x| match x.parse() {
+                    Ok(ranges) => Some(ranges),
+                    Err(e) => {
+                        log::error!("Invalid {}: {}", key, e);
+                        None
+                    }
+                })
+        };
+
+        let field_spec = FieldSpec::new(
+            msg.params
+                .get("delimiter")
+                .and_then(|x| x.as_str().map(Into::into)),
+            parse_field_ranges("nth"),
+            parse_field_ranges("with_nth"),
+        );
+
+        let case_matching = msg
+            .params
+            .get("case_matching")
+            .and_then(|x| x.as_str().map(Into::into))
+            .unwrap_or_default();
+
+        let normalize = msg
+            .params
+            .get("normalize")
+            .and_then(|x| x.as_bool())
+            .unwrap_or(false);
+
+        let bonus_context =
+            BonusContext::new(cwd.clone().into(), Some(start_buffer_path.clone().into()));
+        let bonuses = msg
+            .params
+            .get("bonuses")
+            .and_then(|x| x.as_array())
+            .map(|bonuses| {
+                bonuses
+                    .iter()
+                    .filter_map(|x| x.as_str())
+                    .filter_map(|x| match WeightedBonus::parse(x, &bonus_context) {
+                        Ok(bonus) => Some(bonus),
+                        Err(e) => {
+                            log::error!("Invalid bonus: {}", e);
+                            None
+                        }
+                    })
+                    .collect()
+            });
+
+        let tiebreaks = msg
+            .params
+            .get("tiebreak")
+            .and_then(|x| x.as_str())
+            .map(|x| {
+                x.split(',')
+                    .filter_map(|s| match s.trim().parse() {
+                        Ok(tiebreak) => Some(tiebreak),
+                        Err(e) => {
+                            log::error!("Invalid tiebreak: {}", e);
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let timeout = match msg.params.get("timeout").and_then(|x| x.as_u64()) {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => command_timeout(),
+        };
+
         Self {
             provider_id,
             cwd,
             source_cmd,
             winwidth,
             start_buffer_path,
+            field_spec,
+            case_matching,
+            normalize,
+            bonuses,
+            tiebreaks,
+            timeout,
+            cancel: CancelToken::new(),
             is_running: Arc::new(Mutex::new(true.into())),
             source_list: Arc::new(Mutex::new(None)),
+            displayed: Arc::new(Mutex::new(Vec::new())),
         }
     }
 }
@@ -5,23 +5,53 @@ pub(super) fn run<T: super::handler::HandleMessage>(
     source_cmd: String,
     session: Session<T>,
 ) -> Result<()> {
-    let stdout_stream = filter::subprocess::Exec::shell(source_cmd)
-        .cwd(&session.context.cwd)
-        .stream_stdout()?;
+    let mut cmd = utility::as_std_command(source_cmd, Some(&session.context.cwd));
+    let mut child = utility::SupervisedChild::spawn(
+        &mut cmd,
+        session.context.timeout,
+        session.context.cancel.clone(),
+    )?;
+    let stdout_stream = child
+        .take_stdout()
+        .ok_or_else(|| anyhow::anyhow!("Couldn't capture the stdout of forerunner"))?;
 
-    let lines = std::io::BufReader::new(stdout_stream)
-        .lines()
-        .filter_map(|x| x.ok())
-        .collect::<Vec<String>>();
+    let items = filter::read_byte_lines(
+        std::io::BufReader::new(stdout_stream),
+        filter::Separator::Newline,
+    )
+    .map(|line| session.context.to_source_item(line))
+    .collect::<Vec<_>>();
+
+    // The items are still stored if the command fails by itself, e.g., `rg` exits with 2 on
+    // an unreadable file, and the error is sent along as a warning.
+    let warning = match child.wait() {
+        Ok(_) => None,
+        Err(e) => match e.downcast::<utility::CommandError>() {
+            Ok(error) if error.keeps_output() => Some(error),
+            // The session has been terminated.
+            Ok(error) if error.kind == utility::CommandErrorKind::Cancelled => {
+                debug!("{}", error.message);
+                return Ok(());
+            }
+            Ok(error) => {
+                write_response(json!({
+                "id": msg_id,
+                "provider_id": session.context.provider_id,
+                "error": error,
+                }));
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        },
+    };
 
     if session.is_running() {
         // Send the forerunner result to client.
-        let initial_size = lines.len();
-        let response_lines = lines
+        let initial_size = items.len();
+        let response_lines = items
             .iter()
-            .by_ref()
             .take(30)
-            .map(|line| icon::IconPainter::File.paint(&line))
+            .map(|item| icon::IconPainter::File.paint(item.display_text()))
             .collect::<Vec<_>>();
         write_response(json!({
         "id": msg_id,
@@ -30,10 +60,14 @@ pub(super) fn run<T: super::handler::HandleMessage>(
           "event": "on_init",
           "initial_size": initial_size,
           "lines": response_lines,
+          "warning": warning,
         }}));
 
+        session
+            .context
+            .set_displayed((0..initial_size.min(30)).collect());
         let mut session = session;
-        session.set_source_list(lines);
+        session.set_source_list(items);
     }
 
     Ok(())
@@ -3,8 +3,8 @@ use crate::types::{Message, ProviderId};
 use crate::write_response;
 use anyhow::{anyhow, Context, Result};
 use log::{debug, error};
-use pattern::*;
 use serde_json::json;
+use source_item::{ItemMetadata, SourceItem};
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -37,8 +37,16 @@ pub fn build_abs_path(cwd: &str, curline: String) -> PathBuf {
     path
 }
 
+/// Returns the path and line number in the metadata.
+fn path_and_lnum(metadata: &ItemMetadata) -> Option<(&Path, usize)> {
+    Some((metadata.path.as_deref()?, metadata.lnum?))
+}
+
 impl OnMove {
-    pub fn new(curline: String, context: &SessionContext) -> Result<Self> {
+    /// Constructs the preview environment of `item`, the typed metadata of item is preferred and
+    /// the line is parsed if it's an opaque line.
+    pub fn new(item: SourceItem, context: &SessionContext) -> Result<Self> {
+        let curline = item.raw.clone();
         let context = match context.provider_id.as_str() {
             "files" | "git_files" => Self::Files(build_abs_path(&context.cwd, curline)),
             "history" => {
@@ -56,32 +64,47 @@ impl OnMove {
             }
             "filer" => unreachable!("filer has been handled ahead"),
             "proj_tags" => {
-                let (lnum, p) = extract_proj_tags(&curline).context("can not extract proj tags")?;
+                let metadata = item.metadata_or_parse(ItemMetadata::from_proj_tags_line);
+                let (p, lnum) = metadata
+                    .as_deref()
+                    .and_then(path_and_lnum)
+                    .context("can not extract proj tags")?;
                 let mut path: PathBuf = context.cwd.clone().into();
-                path.push(&p);
+                path.push(p);
                 Self::ProjTags { path, lnum }
             }
             "grep" | "grep2" => {
-                let (fpath, lnum, _col) =
-                    extract_grep_position(&curline).context("Couldn't extract grep position")?;
+                let metadata = item.metadata_or_parse(ItemMetadata::from_grep_line);
+                let (fpath, lnum) = metadata
+                    .as_deref()
+                    .and_then(path_and_lnum)
+                    .context("Couldn't extract grep position")?;
                 let mut path: PathBuf = context.cwd.clone().into();
-                path.push(&fpath);
+                path.push(fpath);
                 Self::Grep { path, lnum }
             }
             "blines" => {
-                let lnum = extract_blines_lnum(&curline).context("can not extract buffer lnum")?;
+                let lnum = item
+                    .metadata_or_parse(ItemMetadata::from_blines_line)
+                    .and_then(|metadata| metadata.lnum)
+                    .context("can not extract buffer lnum")?;
                 let path = context.start_buffer_path.clone().into();
                 Self::BLines { path, lnum }
             }
             "tags" => {
-                let lnum =
-                    extract_buf_tags_lnum(&curline).context("can not extract buffer tags")?;
+                let lnum = item
+                    .metadata_or_parse(ItemMetadata::from_buffer_tags_line)
+                    .and_then(|metadata| metadata.lnum)
+                    .context("can not extract buffer tags")?;
                 let path = context.start_buffer_path.clone().into();
                 Self::BufferTags { path, lnum }
             }
             "commits" | "bcommits" => {
-                let rev = parse_rev(&curline).context("can not extract rev")?;
-                Self::Commit(rev.into())
+                let rev = item
+                    .metadata_or_parse(ItemMetadata::from_commit_line)
+                    .and_then(|metadata| metadata.rev.clone())
+                    .context("can not extract rev")?;
+                Self::Commit(rev)
             }
             _ => {
                 return Err(anyhow!(
@@ -123,7 +146,7 @@ impl<'a> OnMoveHandler<'a> {
             size: provider_id.get_preview_size(),
             provider_id,
             context,
-            inner: OnMove::new(curline, context)?,
+            inner: OnMove::new(context.find_source_item(curline, msg.get_lnum()), context)?,
         })
     }
 
@@ -1,6 +1,6 @@
 use log::debug;
 
-use filter::matcher::{Algo, Bonus, MatchType};
+use filter::matcher::{Algo, Bonus, MatchType, Matcher};
 
 use super::*;
 
@@ -15,24 +15,48 @@ pub fn handle_on_typed(msg: Message, context: &SessionContext) {
     let msg_id = msg.id;
     let query = msg.get_query();
 
-    let source_list = context.source_list.lock().unwrap();
+    // Release the lock as soon as possible, the items are immutable once set.
+    let source_list = context.source_list.lock().unwrap().clone();
 
     // TODO: sync for 100000, dyn for 100000+
-    if let Some(ref source_list) = *source_list {
-        let source = filter::Source::List(source_list.iter().map(|s| s.to_string().into()));
-
+    if let Some(source_list) = source_list {
         let match_type = MatchType::Full;
-        let bonus = match msg.get_provider_id().as_str() {
-            "files" | "git_files" => Bonus::FileName,
-            _ => Bonus::None,
+        let bonuses = match context.bonuses {
+            Some(ref bonuses) => bonuses.clone(),
+            None => match msg.get_provider_id().as_str() {
+                "files" | "git_files" => vec![
+                    Bonus::FileName.into(),
+                    Bonus::Proximity {
+                        cwd: context.cwd.clone().into(),
+                        buffer_path: context.start_buffer_path.clone().into(),
+                    }
+                    .into(),
+                ],
+                _ => vec![],
+            },
         };
-        let lines_info = filter::sync_run(&query, source, Algo::Fzy, match_type, bonus).unwrap();
+        let matcher = Matcher::new(
+            Algo::Fzy,
+            match_type,
+            Bonus::None,
+            context.case_matching.clone(),
+        )
+        .with_bonuses(bonuses)
+        .with_tiebreaks(context.tiebreaks.clone())
+        .with_normalization(context.normalize);
+        let ranked = filter::rank_items(&query, &source_list, &matcher);
+
+        let total = ranked.len();
 
-        let total = lines_info.len();
+        context.set_displayed(ranked.iter().take(30).map(|(idx, ..)| *idx).collect());
 
+        // Only the top items are cloned.
         let (lines, indices, truncated_map) = printer::process_top_items(
             30,
-            lines_info.into_iter().take(30),
+            ranked
+                .into_iter()
+                .take(30)
+                .map(|(idx, score, indices)| (source_list[idx].clone(), score, indices)),
             context.winwidth.map(|x| x as usize),
             Some(icon::IconPainter::File),
         );
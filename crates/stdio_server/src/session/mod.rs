@@ -6,6 +6,7 @@ mod manager;
 use super::*;
 use crate::types::ProviderId;
 use anyhow::Result;
+use source_item::SourceItem;
 
 pub use context::SessionContext;
 pub use handler::{
@@ -33,10 +34,12 @@ pub enum SessionEvent {
 }
 
 impl<T: handler::HandleMessage> Session<T> {
-    /// Sets the running signal to false, in case of the forerunner thread is still working.
+    /// Sets the running signal to false and kills the forerunner command, in case of the
+    /// forerunner thread is still working.
     pub fn handle_terminate(&mut self) {
         let mut val = self.context.is_running.lock().unwrap();
         *val.get_mut() = false;
+        self.context.cancel.cancel();
         debug!(
             "session-{}-{} terminated",
             self.session_id,
@@ -55,9 +58,9 @@ impl<T: handler::HandleMessage> Session<T> {
 
     /// Saves the forerunner result.
     /// TODO: Store full lines, or a cached file?
-    pub fn set_source_list(&mut self, lines: Vec<String>) {
+    pub fn set_source_list(&mut self, items: Vec<SourceItem>) {
         let mut source_list = self.context.source_list.lock().unwrap();
-        *source_list = Some(lines);
+        *source_list = Some(items.into());
     }
 
     pub fn provider_id(&self) -> &ProviderId {
@@ -63,6 +63,14 @@ impl Message {
         self._get_string_unsafe("cwd")
     }
 
+    /// Returns the 1-based line number of the current line in display window.
+    pub fn get_lnum(&self) -> Option<usize> {
+        self.params
+            .get("lnum")
+            .and_then(|x| x.as_u64())
+            .map(|x| x as usize)
+    }
+
     /// Get the current line of display window without the leading icon.
     pub fn get_curline(&self, provider_id: &ProviderId) -> anyhow::Result<String> {
         let display_curline = self._get_string("curline")?;
@@ -0,0 +1,202 @@
+//! Location of the cache files of maple.
+//!
+//! The cache dir is resolved in the following order:
+//!
+//! 1. The env var `CLAP_CACHE_DIR`, which is also set by `maple --cache-dir`.
+//! 2. `cache.dir` in the config file `$XDG_CONFIG_HOME/vim-clap/config.toml`, see [`Config`].
+//! 3. `$XDG_CACHE_HOME/vim-clap`, or `~/.cache/vim-clap` if `XDG_CACHE_HOME` is unset,
+//!    `%LOCALAPPDATA%\vim-clap` on Windows.
+//!
+//! The cache dir is only accessible to the current user, the entries of the legacy cache dir
+//! `temp_dir/vim.clap` owned by the current user are removed on the first use.
+
+use crate::CLAP_CACHE;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+/// Env var for overriding the cache dir.
+pub const CLAP_CACHE_DIR_ENV: &str = "CLAP_CACHE_DIR";
+
+const APP_NAME: &str = "vim-clap";
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct CacheConfig {
+    pub dir: Option<PathBuf>,
+    pub compress: Option<bool>,
+}
+
+/// Config file of maple, e.g.,
+///
+/// ```toml
+/// [cache]
+/// dir = "~/.cache/vim-clap"
+/// compress = true
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct Config {
+    #[serde(default)]
+    pub cache: CacheConfig,
+}
+
+fn env_dir(key: &str) -> Option<PathBuf> {
+    std::env::var_os(key)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env_dir("HOME").or_else(|| env_dir("USERPROFILE"))
+}
+
+/// Expands the leading `~` of `path`.
+fn expand_tilde(path: PathBuf) -> PathBuf {
+    match (path.strip_prefix("~"), home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path,
+    }
+}
+
+/// Returns the path of config file `$XDG_CONFIG_HOME/vim-clap/config.toml`.
+pub fn config_file() -> Option<PathBuf> {
+    let config_dir = if cfg!(windows) {
+        env_dir("APPDATA")
+    } else {
+        env_dir("XDG_CONFIG_HOME").or_else(|| home_dir().map(|home| home.join(".config")))
+    };
+    config_dir.map(|dir| dir.join(APP_NAME).join("config.toml"))
+}
+
+fn read_config(config_file: &Path) -> Option<Config> {
+    let contents = std::fs::read_to_string(config_file).ok()?;
+    toml::from_str(&contents).ok()
+}
+
+/// Returns the config read from [`config_file`], the default one if it's absent or invalid.
+pub(crate) fn config() -> Config {
+    config_file()
+        .as_deref()
+        .and_then(read_config)
+        .unwrap_or_default()
+}
+
+fn default_cache_dir() -> Option<PathBuf> {
+    let cache_home = if cfg!(windows) {
+        env_dir("LOCALAPPDATA")
+    } else {
+        env_dir("XDG_CACHE_HOME").or_else(|| home_dir().map(|home| home.join(".cache")))
+    };
+    cache_home.map(|dir| dir.join(APP_NAME))
+}
+
+/// Returns the legacy cache dir shared by all the users.
+pub fn legacy_cache_dir() -> PathBuf {
+    std::env::temp_dir().join(CLAP_CACHE)
+}
+
+fn resolve_cache_dir() -> PathBuf {
+    env_dir(CLAP_CACHE_DIR_ENV)
+        .map(expand_tilde)
+        .or_else(|| config().cache.dir.map(expand_tilde))
+        .or_else(default_cache_dir)
+        .unwrap_or_else(legacy_cache_dir)
+}
+
+/// Creates `dir` which is only accessible to the current user.
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// Returns true if `path` is owned by the owner of `dir`.
+#[cfg(unix)]
+fn same_owner(path: &Path, dir: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::metadata(path), std::fs::metadata(dir)) {
+        (Ok(a), Ok(b)) => a.uid() == b.uid(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_owner(_path: &Path, _dir: &Path) -> bool {
+    true
+}
+
+/// Removes the entries of `legacy_dir` owned by the owner of `cache_dir`.
+///
+/// The legacy entries are keyed by the command and a hash of the working dir, which can not be
+/// converted to the cache keys of `cache_dir` and would never be hit again. It's best-effort as
+/// the entries of the other users are kept.
+pub fn remove_legacy_entries(legacy_dir: &Path, cache_dir: &Path) {
+    let entries = match read_dir(legacy_dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !same_owner(&path, cache_dir) {
+            continue;
+        }
+        let _ = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+    }
+    // Only removed if it's empty.
+    let _ = std::fs::remove_dir(legacy_dir);
+}
+
+static CACHE_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    let cache_dir = resolve_cache_dir();
+    let legacy_dir = legacy_cache_dir();
+    if cache_dir != legacy_dir {
+        let is_new = !cache_dir.exists();
+        if create_private_dir(&cache_dir).is_ok() && is_new {
+            remove_legacy_entries(&legacy_dir, &cache_dir);
+        }
+    }
+    cache_dir
+});
+
+/// Returns the cache dir of maple, see the module docs for how it's resolved.
+#[inline]
+pub fn clap_cache_dir() -> PathBuf {
+    CACHE_DIR.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_and_legacy_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let config = root.join("config.toml");
+        let config_cache_dir = |config: &Path| read_config(config).and_then(|c| c.cache.dir);
+        std::fs::write(&config, "[cache]\ndir = \"/var/cache/clap\"\n").unwrap();
+        assert_eq!(
+            config_cache_dir(&config),
+            Some(PathBuf::from("/var/cache/clap"))
+        );
+        std::fs::write(&config, "[other]\nkey = 1\n").unwrap();
+        assert_eq!(config_cache_dir(&config), None);
+
+        let legacy_dir = root.join("vim.clap");
+        let cache_dir = root.join("vim-clap");
+        std::fs::create_dir_all(legacy_dir.join("rg_--files").join("123")).unwrap();
+        std::fs::write(legacy_dir.join("rg_--files/123/1600000000_1"), "a\n").unwrap();
+        create_private_dir(&cache_dir).unwrap();
+        remove_legacy_entries(&legacy_dir, &cache_dir);
+        assert!(!cache_dir.join("rg_--files").exists());
+        assert!(!legacy_dir.exists());
+    }
+}
@@ -0,0 +1,137 @@
+//! Stable keys of the cache entries.
+//!
+//! The key is a hash over the exact command arguments, the working directory and the environment
+//! affecting the output of command, i.e., the env vars in [`CACHE_KEY_ENV_VARS`] and the ignore
+//! files in the working directory. The hash function is FNV-1a, which unlike `DefaultHasher`
+//! does not change across Rust versions, so the cache keeps valid after upgrading maple.
+
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::hash::Hasher;
+use std::path::Path;
+
+/// Env vars which can change the output of the cached commands.
+pub const CACHE_KEY_ENV_VARS: &[&str] = &[
+    "RIPGREP_CONFIG_PATH",
+    "FZF_DEFAULT_COMMAND",
+    "GIT_DIR",
+    "GIT_WORK_TREE",
+];
+
+/// Ignore files in the working directory respected by rg, fd and git.
+const IGNORE_FILES: &[&str] = &[".gitignore", ".ignore", ".rgignore", ".fdignore"];
+
+/// 64-bit FNV-1a hasher, whose result is stable across platforms and Rust versions.
+#[derive(Debug, Clone, Copy)]
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl StableHasher {
+    /// Writes `bytes` followed by a terminator, so that the adjacent fields can not collide,
+    /// e.g., `["ab", "c"]` and `["a", "bc"]`.
+    pub fn write_field(&mut self, bytes: &[u8]) {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+}
+
+pub(crate) fn os_bytes(s: &OsStr) -> Cow<'_, [u8]> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Cow::Borrowed(s.as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        match s.to_string_lossy() {
+            Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+            Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+        }
+    }
+}
+
+/// Returns the values of env vars in [`CACHE_KEY_ENV_VARS`] which are set.
+pub fn cache_key_env() -> Vec<(String, String)> {
+    CACHE_KEY_ENV_VARS
+        .iter()
+        .filter_map(|name| {
+            std::env::var_os(name).map(|v| (name.to_string(), v.to_string_lossy().into_owned()))
+        })
+        .collect()
+}
+
+/// Returns the key of cache entry in hex, see the module docs.
+pub fn cache_key(args: &[&str], cmd_dir: Option<&Path>) -> String {
+    let mut hasher = StableHasher::default();
+
+    hasher.write_field(b"args");
+    for arg in args {
+        hasher.write_field(arg.as_bytes());
+    }
+
+    if let Some(cmd_dir) = cmd_dir {
+        hasher.write_field(b"cwd");
+        hasher.write_field(&os_bytes(cmd_dir.as_os_str()));
+        for ignore_file in IGNORE_FILES {
+            if let Ok(contents) = std::fs::read(cmd_dir.join(ignore_file)) {
+                hasher.write_field(ignore_file.as_bytes());
+                hasher.write_field(&contents);
+            }
+        }
+    }
+
+    for name in CACHE_KEY_ENV_VARS {
+        if let Some(value) = std::env::var_os(name) {
+            hasher.write_field(name.as_bytes());
+            hasher.write_field(&os_bytes(&value));
+        }
+    }
+
+    format!("{:016x}", hasher.finish())
+}
+
+/// Returns true if `name` is in the form of cache key.
+pub fn is_cache_key(name: &str) -> bool {
+    name.len() == 16 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key() {
+        let mut hasher = StableHasher::default();
+        hasher.write(b"foobar");
+        // The well-known FNV-1a value, which must never change.
+        assert_eq!(hasher.finish(), 0x85944171f73967e8);
+
+        let key = cache_key(&["rg", "--files"], None);
+        assert!(is_cache_key(&key));
+        assert_eq!(key, cache_key(&["rg", "--files"], None));
+        assert_ne!(key, cache_key(&["rg", "--fil", "es"], None));
+        assert_ne!(key, cache_key(&["rg --files"], None));
+        assert_ne!(
+            cache_key(&["rg"], Some(Path::new("/a"))),
+            cache_key(&["rg"], Some(Path::new("/b")))
+        );
+    }
+}
@@ -0,0 +1,375 @@
+//! Supervised execution of the external commands.
+//!
+//! The spawned command is killed along with its child processes, e.g., the commands of a shell
+//! pipeline, once it runs longer than the timeout or it's cancelled via [`CancelToken`]. The
+//! failure is reported as a [`CommandError`] carrying the exit status and an excerpt of stderr,
+//! which can be sent to the client as is.
+
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Read};
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Env var for the timeout of the spawned commands in seconds, 0 means no timeout.
+pub const CLAP_COMMAND_TIMEOUT_ENV: &str = "CLAP_COMMAND_TIMEOUT";
+
+/// Max number of the trailing bytes of stderr kept in [`CommandError`].
+pub const STDERR_EXCERPT_LEN: usize = 4096;
+
+/// Interval of checking the timeout and cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Returns the timeout of the spawned commands set by the env var `CLAP_COMMAND_TIMEOUT`.
+pub fn command_timeout() -> Option<Duration> {
+    std::env::var(CLAP_COMMAND_TIMEOUT_ENV)
+        .ok()
+        .and_then(|secs| secs.trim().parse::<u64>().ok())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Handle for cancelling the supervised command from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Kills the commands supervised with this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandErrorKind {
+    /// The command exited with a non-zero status and wrote to stderr.
+    Failed,
+    /// The command was killed as it ran longer than the timeout.
+    Timeout,
+    /// The command was killed via [`CancelToken`].
+    Cancelled,
+}
+
+/// Error of the supervised command.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandError {
+    pub kind: CommandErrorKind,
+    /// Human-readable summary of the error.
+    pub message: String,
+    pub command: String,
+    /// Exit code of the command, absent if it's killed by a signal.
+    pub exit_code: Option<i32>,
+    /// The trailing [`STDERR_EXCERPT_LEN`] bytes of stderr at most.
+    pub stderr: String,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.stderr.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}\n{}", self.message, self.stderr)
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl CommandError {
+    /// Returns true if the output of command is still usable, i.e., it exits with a failure by
+    /// itself, e.g., `rg` exits with 2 on an unreadable file but the matches are printed. The
+    /// output of the killed command is incomplete.
+    pub fn keeps_output(&self) -> bool {
+        self.kind == CommandErrorKind::Failed
+    }
+}
+
+/// Returns the command line of `cmd` for displaying.
+fn describe(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Reads `reader` to the end and returns the trailing `limit` bytes, along with whether the
+/// leading bytes are dropped.
+fn read_tail<R: Read>(mut reader: R, limit: usize) -> (Vec<u8>, bool) {
+    let mut tail = Vec::new();
+    let mut buf = [0u8; 8192];
+    let mut truncated = false;
+    while let Ok(n) = reader.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        tail.extend_from_slice(&buf[..n]);
+        if tail.len() > 2 * limit {
+            tail.drain(..tail.len() - limit);
+            truncated = true;
+        }
+    }
+    if tail.len() > limit {
+        tail.drain(..tail.len() - limit);
+        truncated = true;
+    }
+    (tail, truncated)
+}
+
+/// Kills the process group of command, so that its child processes are killed too.
+#[cfg(unix)]
+fn kill_tree(pid: u32) {
+    // The command is the leader of its own process group, see `SupervisedChild::spawn`.
+    unsafe {
+        libc::kill(-(pid as i32), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_tree(pid: u32) {
+    let _ = Command::new("taskkill")
+        .args(&["/F", "/T", "/PID", &pid.to_string()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+}
+
+/// Command spawned with a watchdog thread, which kills it once timed out or cancelled.
+pub struct SupervisedChild {
+    child: Child,
+    command: String,
+    timeout: Option<Duration>,
+    stdout: Option<ChildStdout>,
+    stderr: Option<JoinHandle<(Vec<u8>, bool)>>,
+    /// Stops the watchdog once dropped.
+    done: Option<mpsc::Sender<()>>,
+    watchdog: Option<JoinHandle<Option<CommandErrorKind>>>,
+}
+
+impl SupervisedChild {
+    /// Spawns `cmd` with the piped stdout and stderr, which is killed if it does not exit in
+    /// `timeout` or `cancel` is cancelled.
+    pub fn spawn(
+        cmd: &mut Command,
+        timeout: Option<Duration>,
+        cancel: CancelToken,
+    ) -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            cmd.process_group(0);
+        }
+
+        let mut child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Drain stderr concurrently, otherwise the command could block on writing to a full pipe.
+        let stderr = child
+            .stderr
+            .take()
+            .map(|stderr| thread::spawn(move || read_tail(stderr, STDERR_EXCERPT_LEN)));
+
+        let (done, done_recv) = mpsc::channel::<()>();
+        let pid = child.id();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let watchdog = thread::spawn(move || loop {
+            let killed = if cancel.is_cancelled() {
+                Some(CommandErrorKind::Cancelled)
+            } else if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                Some(CommandErrorKind::Timeout)
+            } else {
+                None
+            };
+            if killed.is_some() {
+                kill_tree(pid);
+                return killed;
+            }
+            if let Err(RecvTimeoutError::Timeout) = done_recv.recv_timeout(POLL_INTERVAL) {
+                continue;
+            }
+            return None;
+        });
+
+        Ok(Self {
+            stdout: child.stdout.take(),
+            child,
+            command: describe(cmd),
+            timeout,
+            stderr,
+            done: Some(done),
+            watchdog: Some(watchdog),
+        })
+    }
+
+    /// Takes the stdout of command, which should be read to the end or dropped before
+    /// [`Self::wait`].
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.stdout.take()
+    }
+
+    /// Stops the watchdog, returns the reason if it has killed the command.
+    ///
+    /// The command is only reaped after the watchdog stops, so that the watchdog never kills
+    /// a reused pid.
+    fn stop_watchdog(&mut self) -> Option<CommandErrorKind> {
+        self.done.take();
+        self.watchdog
+            .take()
+            .and_then(|watchdog| watchdog.join().ok())
+            .flatten()
+    }
+
+    /// Waits for the command to exit.
+    ///
+    /// Returns [`CommandError`] if it's killed, or it exits with a non-zero status and writes
+    /// to stderr. A non-zero status without stderr is not an error, e.g., `rg` exits with 1
+    /// if nothing is matched.
+    pub fn wait(mut self) -> anyhow::Result<ExitStatus> {
+        self.stdout.take();
+        let killed = self.stop_watchdog();
+        let status = self.child.wait()?;
+        let (stderr, truncated) = self
+            .stderr
+            .take()
+            .and_then(|stderr| stderr.join().ok())
+            .unwrap_or_default();
+
+        let message = match killed {
+            Some(CommandErrorKind::Timeout) => format!(
+                "`{}` timed out after {}s",
+                self.command,
+                self.timeout.map(|t| t.as_secs()).unwrap_or_default()
+            ),
+            Some(CommandErrorKind::Cancelled) => format!("`{}` was cancelled", self.command),
+            Some(CommandErrorKind::Failed) | None => {
+                if status.success() || stderr.is_empty() {
+                    return Ok(status);
+                }
+                match status.code() {
+                    Some(code) => format!("`{}` exited with status {}", self.command, code),
+                    None => format!("`{}` was terminated by a signal", self.command),
+                }
+            }
+        };
+
+        let mut stderr_excerpt = String::from_utf8_lossy(&stderr).into_owned();
+        if truncated {
+            stderr_excerpt.insert_str(0, "...");
+        }
+        Err(CommandError {
+            kind: killed.unwrap_or(CommandErrorKind::Failed),
+            message,
+            command: std::mem::take(&mut self.command),
+            exit_code: status.code(),
+            stderr: stderr_excerpt,
+        }
+        .into())
+    }
+}
+
+impl Drop for SupervisedChild {
+    fn drop(&mut self) {
+        // Not waited, e.g., the reader of stdout fails.
+        if self.watchdog.is_some() {
+            self.stop_watchdog();
+            kill_tree(self.child.id());
+            let _ = self.child.wait();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        cmd
+    }
+
+    fn error_of(child: SupervisedChild) -> CommandError {
+        child
+            .wait()
+            .unwrap_err()
+            .downcast::<CommandError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_supervised_child() {
+        let mut child =
+            SupervisedChild::spawn(&mut sh("echo foo"), None, CancelToken::new()).unwrap();
+        let mut stdout = String::new();
+        child
+            .take_stdout()
+            .unwrap()
+            .read_to_string(&mut stdout)
+            .unwrap();
+        assert_eq!(stdout, "foo\n");
+        assert!(child.wait().unwrap().success());
+
+        // Exiting with 1 silently is not an error.
+        let child = SupervisedChild::spawn(&mut sh("exit 1"), None, CancelToken::new()).unwrap();
+        assert_eq!(child.wait().unwrap().code(), Some(1));
+
+        let child =
+            SupervisedChild::spawn(&mut sh("echo oops >&2; exit 2"), None, CancelToken::new())
+                .unwrap();
+        let error = error_of(child);
+        assert_eq!(error.kind, CommandErrorKind::Failed);
+        assert_eq!(error.exit_code, Some(2));
+        assert_eq!(error.stderr, "oops\n");
+        assert!(error.keeps_output());
+
+        // The whole pipeline is killed, otherwise reading stdout would block.
+        let started = Instant::now();
+        let mut child = SupervisedChild::spawn(
+            &mut sh("sleep 30 | cat"),
+            Some(Duration::from_millis(200)),
+            CancelToken::new(),
+        )
+        .unwrap();
+        child
+            .take_stdout()
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap();
+        let error = error_of(child);
+        assert_eq!(error.kind, CommandErrorKind::Timeout);
+        assert!(!error.keeps_output());
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let cancel = CancelToken::new();
+        let child = SupervisedChild::spawn(&mut sh("sleep 30"), None, cancel.clone()).unwrap();
+        cancel.cancel();
+        let error = error_of(child);
+        assert_eq!(error.kind, CommandErrorKind::Cancelled);
+        assert_eq!(error.exit_code, None);
+    }
+
+    #[test]
+    fn test_read_tail() {
+        let (tail, truncated) = read_tail(&b"abcdef"[..], 4);
+        assert_eq!(tail, b"cdef");
+        assert!(truncated);
+        assert_eq!(read_tail(&b"ab"[..], 4), (b"ab".to_vec(), false));
+    }
+}
@@ -0,0 +1,124 @@
+//! Transparent compression of the cache files.
+//!
+//! The cache files are compressed in the LZ4 frame format if enabled by the env var
+//! `CLAP_CACHE_COMPRESS=1` or `cache.compress = true` in the config file. The compressed files
+//! are recognized by the magic number of LZ4 frame, so the readers handle both the compressed and
+//! the plain cache files.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+/// Env var for enabling the compression of cache files.
+pub const CLAP_CACHE_COMPRESS_ENV: &str = "CLAP_CACHE_COMPRESS";
+
+/// Magic number of the LZ4 frame format.
+const LZ4_MAGIC: [u8; 4] = [0x04, 0x22, 0x4d, 0x18];
+
+/// Returns true if the cache files should be compressed.
+pub fn compression_enabled() -> bool {
+    match std::env::var(CLAP_CACHE_COMPRESS_ENV) {
+        Ok(v) => v == "1" || v.eq_ignore_ascii_case("true"),
+        Err(_) => crate::cache_dir::config().cache.compress.unwrap_or(false),
+    }
+}
+
+/// Returns true if `bytes` starts with the magic number of LZ4 frame.
+pub fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.starts_with(&LZ4_MAGIC)
+}
+
+/// Compresses `contents` in the LZ4 frame format.
+pub fn compress(contents: &[u8]) -> io::Result<Vec<u8>> {
+    let mut writer = CacheWriter::new(Vec::with_capacity(contents.len() / 4), true);
+    writer.write_all(contents)?;
+    writer.finish()
+}
+
+/// Writer of the cache file by streaming, which compresses the contents if required.
+pub enum CacheWriter<W: Write> {
+    Plain(W),
+    Compressed(Box<lz4_flex::frame::FrameEncoder<W>>),
+}
+
+impl<W: Write> CacheWriter<W> {
+    pub fn new(inner: W, compress: bool) -> Self {
+        if compress {
+            Self::Compressed(Box::new(lz4_flex::frame::FrameEncoder::new(inner)))
+        } else {
+            Self::Plain(inner)
+        }
+    }
+
+    /// Writes the end of the compressed frame if any, returns the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Plain(inner) => Ok(inner),
+            Self::Compressed(encoder) => Ok(encoder.finish()?),
+        }
+    }
+}
+
+impl<W: Write> Write for CacheWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(inner) => inner.write(buf),
+            Self::Compressed(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(inner) => inner.flush(),
+            Self::Compressed(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Opens the file for reading, which is decompressed by streaming if it's compressed.
+pub fn open_decompressed<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn BufRead + Send>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if is_compressed(reader.fill_buf()?) {
+        Ok(Box::new(BufReader::new(
+            lz4_flex::frame::FrameDecoder::new(reader),
+        )))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_open_decompressed() {
+        let contents = "src/lib.rs:1:1:use std::io;\n".repeat(1000);
+        let compressed = compress(contents.as_bytes()).unwrap();
+        assert!(is_compressed(&compressed));
+        assert!(compressed.len() < contents.len() / 10);
+        assert!(!is_compressed(contents.as_bytes()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let compressed_file = dir.path().join("compressed");
+        let plain_file = dir.path().join("plain");
+        std::fs::write(&compressed_file, &compressed).unwrap();
+        std::fs::write(&plain_file, &contents).unwrap();
+
+        let read = |path: &Path| {
+            let mut buf = Vec::new();
+            open_decompressed(path)
+                .unwrap()
+                .read_to_end(&mut buf)
+                .unwrap();
+            buf
+        };
+        assert_eq!(read(&compressed_file), contents.as_bytes());
+        assert_eq!(read(&plain_file), contents.as_bytes());
+        assert_eq!(
+            open_decompressed(&compressed_file).unwrap().lines().count(),
+            1000
+        );
+    }
+}
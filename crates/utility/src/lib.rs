@@ -9,6 +9,26 @@ use std::io::{self, BufRead};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
 
+mod cache_dir;
+mod cache_key;
+mod command;
+mod compress;
+
+pub use cache_dir::{
+    clap_cache_dir, config_file, legacy_cache_dir, remove_legacy_entries, CLAP_CACHE_DIR_ENV,
+};
+use cache_key::os_bytes;
+pub use cache_key::{cache_key, cache_key_env, is_cache_key, StableHasher, CACHE_KEY_ENV_VARS};
+pub use command::{
+    command_timeout, CancelToken, CommandError, CommandErrorKind, SupervisedChild,
+    CLAP_COMMAND_TIMEOUT_ENV, STDERR_EXCERPT_LEN,
+};
+pub use compress::{
+    compress, compression_enabled, is_compressed, open_decompressed, CacheWriter,
+    CLAP_CACHE_COMPRESS_ENV,
+};
+
+/// Name of the legacy cache dir under `std::env::temp_dir()`.
 pub const CLAP_CACHE: &str = "vim.clap";
 
 /// Removes all the file and directories under `target_dir`.
@@ -35,6 +55,66 @@ pub fn is_git_repo(dir: &Path) -> bool {
     gitdir.exists()
 }
 
+/// Max number of the entries hashed by [`dir_fingerprint`] for a directory not in git.
+const MAX_FINGERPRINT_ENTRIES: usize = 10_000;
+
+/// Returns the fingerprint of the contents of `dir`, used for checking the freshness of cache.
+///
+/// For a git repo, it's calculated from the current HEAD and the mtime of git index, otherwise
+/// from the paths and mtimes of the entries in `dir` walked breadth-first, at most
+/// [`MAX_FINGERPRINT_ENTRIES`] entries are walked so the changes deep in a huge directory may
+/// be missed.
+pub fn dir_fingerprint(dir: &Path) -> Option<u64> {
+    // Nanoseconds since UNIX_EPOCH, or 0 if unavailable.
+    let nanos = |metadata: std::fs::Metadata| {
+        metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+    };
+    let mtime = |path: &Path| std::fs::metadata(path).ok().and_then(nanos);
+
+    let mut hasher = StableHasher::default();
+    let git_dir = dir.join(".git");
+    if git_dir.is_dir() {
+        let head = std::fs::read_to_string(git_dir.join("HEAD")).ok()?;
+        hasher.write_field(head.as_bytes());
+        // HEAD is usually a symbolic ref, e.g., `ref: refs/heads/master`.
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            let commit = std::fs::read(git_dir.join(reference)).unwrap_or_default();
+            hasher.write_field(&commit);
+        }
+        hasher.write_field(&mtime(&git_dir.join("index")).unwrap_or(0).to_le_bytes());
+    } else {
+        hasher.write_field(&mtime(dir)?.to_le_bytes());
+        let mut budget = MAX_FINGERPRINT_ENTRIES;
+        let mut pending = std::collections::VecDeque::from(vec![dir.to_path_buf()]);
+        'walk: while let Some(current) = pending.pop_front() {
+            let mut entries = match read_dir(&current) {
+                Ok(entries) => entries.filter_map(|entry| entry.ok()).collect::<Vec<_>>(),
+                Err(_) => continue,
+            };
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                if budget == 0 {
+                    break 'walk;
+                }
+                budget -= 1;
+                let path = entry.path();
+                // The symlinks are not followed.
+                let metadata = entry.metadata().ok();
+                if metadata.as_ref().map(|m| m.is_dir()).unwrap_or(false) {
+                    pending.push_back(path.clone());
+                }
+                hasher.write_field(&os_bytes(path.as_os_str()));
+                hasher.write_field(&metadata.and_then(nanos).unwrap_or(0).to_le_bytes());
+            }
+        }
+    }
+    Some(hasher.finish())
+}
+
 // The output is wrapped in a Result to allow matching on errors
 // Returns an Iterator to the Reader of the lines of the file.
 pub fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
@@ -63,25 +143,14 @@ pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
     s.finish()
 }
 
-#[inline]
-pub fn clap_cache_dir() -> PathBuf {
-    let mut dir = std::env::temp_dir();
-    dir.push(CLAP_CACHE);
-    dir
-}
-
 /// Returns the cache path for clap.
 ///
-/// The reason for using hash(cmd_dir) instead of cmd_dir directory is to avoid the possible issue
-/// of using a path as the directory name.
+/// The human-readable command and working directory are recorded in the cache manifest instead
+/// of the path, which avoids the issues of using the arbitrary arguments as the directory name.
 ///
-/// Formula: temp_dir + clap_cache + arg1_arg2_arg3 + hash(cmd_dir)
-pub fn get_cache_dir(args: &[&str], cmd_dir: &PathBuf) -> PathBuf {
-    let mut dir = clap_cache_dir();
-    dir.push(args.join("_"));
-    // TODO: use a readable cache cmd_dir name?
-    dir.push(format!("{}", calculate_hash(&cmd_dir)));
-    dir
+/// Formula: clap_cache_dir + cache_key(args, cmd_dir)
+pub fn get_cache_dir(args: &[&str], cmd_dir: &Path) -> PathBuf {
+    clap_cache_dir().join(cache_key(args, Some(cmd_dir)))
 }
 
 /// Returns the cached entry given the cmd args and working dir.
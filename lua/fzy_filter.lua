@@ -53,6 +53,14 @@ local function match_text_for(item, match_type)
   end
 end
 
+-- Converts the 0-based byte index of s to the 0-based char index.
+--
+-- The matched indices are char indices, which is consistent with the Rust backend.
+local function char_index_of(s, byte_idx)
+    local _, count = string.gsub(s:sub(1, byte_idx), "[^\128-\191]", "")
+    return count
+end
+
 local function apply_fzy(query, candidates, enable_icon, match_type)
     if string.match(query, '%u') then
         case_sensitive = true
@@ -82,7 +90,7 @@ local function apply_fzy(query, candidates, enable_icon, match_type)
             if score ~= fzy.get_score_min() then
                 adjusted_positions = {}
                 for i, v in ipairs(positions) do
-                    adjusted_positions[i] = v + offset
+                    adjusted_positions[i] = char_index_of(raw_c, v + offset)
                 end
                 table.insert(matches, {text = raw_c, score = score, indices = adjusted_positions})
             end
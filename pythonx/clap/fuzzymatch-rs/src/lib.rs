@@ -31,13 +31,16 @@ fn fuzzy_match(
         },
         match_type.into(),
         bonus.into(),
+        Default::default(),
     );
     let do_match = |line: &str| {
         if enable_icon {
-            // " " is 4 bytes, but the offset of highlight is 2.
+            // Skip the icon and the following space, the offset of highlight is 2 as the matched
+            // indices are char indices.
+            let (byte_offset, _) = line.char_indices().nth(2)?;
             matcher
-                .do_match(&line[4..].into(), query)
-                .map(|(score, indices)| (score, indices.into_iter().map(|x| x + 4).collect()))
+                .do_match(&line[byte_offset..].into(), query)
+                .map(|(score, indices)| (score, indices.into_iter().map(|x| x + 2).collect()))
         } else {
             matcher.do_match(&line.into(), query)
         }
@@ -26,7 +26,7 @@ def apply_score(scorer, query, candidates, enable_icon):
         score, indices = scorer(query, candidate)
         if score != float("-inf"):
             if enable_icon:
-                indices = [x + 4 for x in indices]
+                indices = [x + 2 for x in indices]
             scored.append({'score': score, 'indices': indices, 'text': c})
 
     return scored